@@ -0,0 +1,35 @@
+#!/bin/sh
+# Compares the throughput of piece digests taken from a snapshot of the
+# hasher's state, as this tree does, with them taken by finishing a clone
+# of the whole hasher, as clone-digest.patch puts back.
+#
+# Builds the committed tree twice in a scratch directory, once with the
+# patch applied, and runs `cargo bench` on each in turn ROUNDS times
+# (default 5), printing every result.  Needs the toolchain the crate is
+# written for, Rust 1.0.0-alpha and its cargo.
+#
+#     benches/compare-digest.sh
+#     ROUNDS=21 benches/compare-digest.sh > results.txt
+
+set -e
+
+: ${ROUNDS:=5}
+
+cd "$(dirname "$0")/.."
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+for tree in snapshot clone; do
+    mkdir "$work/$tree"
+    git archive HEAD | tar -x -C "$work/$tree"
+done
+(cd "$work/clone" && patch -p1 -s < benches/clone-digest.patch)
+
+echo "# $(git rev-parse --short HEAD), $(rustc --version)"
+round=1
+while [ "$round" -le "$ROUNDS" ]; do
+    for tree in clone snapshot; do
+        (cd "$work/$tree" && cargo bench 2>/dev/null) | grep 'MB/s' | sed "s/^/$tree: /"
+    done
+    round=$((round + 1))
+done
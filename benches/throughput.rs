@@ -0,0 +1,93 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Throughput of the stream's hot paths.  `cargo bench` reports each in
+//! MB/s; compare runs across commits to see what a change is worth.
+
+extern crate test;
+extern crate reliable_rw;
+
+use std::io::BufReader;
+use std::io::util::NullWriter;
+use test::Bencher;
+use reliable_rw::{copy_out, sha256_of, EncapOptions, ReliableEncap, PIECE_SIZE};
+
+
+/// 4 MiB, enough pieces that per-stream setup doesn't count.
+static PAYLOAD_SIZE: uint = 4 * 1024 * 1024;
+
+
+fn payload() -> Vec<u8> {
+    range(0, PAYLOAD_SIZE).map(|i| (i * 7) as u8).collect()
+}
+
+
+fn encode(payload: &Vec<u8>, piece_size: uint) -> Vec<u8> {
+    let mut stream = Vec::with_capacity(payload.len() + payload.len() / 16 + 1024);
+    {
+        let mut encapper = EncapOptions::new().piece_size(piece_size).encap(&mut stream).unwrap();
+        encapper.update(payload).unwrap();
+        encapper.finish_write().unwrap();
+        encapper.finalize().unwrap();
+    }
+    stream
+}
+
+
+#[bench]
+fn sha256(b: &mut Bencher) {
+    let payload = payload();
+    b.bytes = payload.len() as u64;
+    b.iter(|| sha256_of(&mut BufReader::new(payload.as_slice())).unwrap());
+}
+
+
+#[bench]
+fn encode_default_pieces(b: &mut Bencher) {
+    let payload = payload();
+    b.bytes = payload.len() as u64;
+    b.iter(|| {
+        let mut output = NullWriter;
+        let mut encapper = ReliableEncap::new(&mut output).unwrap();
+        encapper.update(&payload).unwrap();
+        encapper.finish_write().unwrap();
+        encapper.finalize().unwrap();
+    });
+}
+
+
+/// Small pieces make the per-piece digest, rather than the hashing of
+/// the data, the bulk of the work.
+#[bench]
+fn encode_small_pieces(b: &mut Bencher) {
+    let payload = payload();
+    b.bytes = payload.len() as u64;
+    b.iter(|| {
+        let mut output = NullWriter;
+        let mut encapper = EncapOptions::new().piece_size(64).encap(&mut output).unwrap();
+        encapper.update(&payload).unwrap();
+        encapper.finish_write().unwrap();
+        encapper.finalize().unwrap();
+    });
+}
+
+
+#[bench]
+fn decode_default_pieces(b: &mut Bencher) {
+    let stream = encode(&payload(), PIECE_SIZE);
+    b.bytes = PAYLOAD_SIZE as u64;
+    b.iter(|| copy_out(&mut BufReader::new(stream.as_slice()), &mut NullWriter).ok().unwrap());
+}
+
+
+#[bench]
+fn decode_small_pieces(b: &mut Bencher) {
+    let stream = encode(&payload(), 64);
+    b.bytes = PAYLOAD_SIZE as u64;
+    b.iter(|| copy_out(&mut BufReader::new(stream.as_slice()), &mut NullWriter).ok().unwrap());
+}
@@ -0,0 +1,46 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Builds the accelerated SHA-256 backends.  Which one is used is decided
+//! at runtime, so the C is compiled the same way everywhere.
+//!
+//! Every release of the `gcc` crate old enough for our toolchain has been
+//! yanked, so this runs `$CC` and `$AR` itself.
+
+use std::os;
+use std::io::Command;
+use std::io::process::InheritFd;
+
+
+fn run(cmd: &mut Command) {
+    let status = match cmd.stdout(InheritFd(2)).stderr(InheritFd(2)).status() {
+        Ok(status) => status,
+        Err(err) => panic!("could not run {}: {}", cmd, err)
+    };
+    if !status.success() {
+        panic!("{} failed: {}", cmd, status);
+    }
+}
+
+
+fn main() {
+    let out_dir = Path::new(os::getenv("OUT_DIR").expect("OUT_DIR is not set"));
+    let cc = os::getenv("CC").unwrap_or("cc".to_string());
+    let ar = os::getenv("AR").unwrap_or("ar".to_string());
+    let object = out_dir.join("sha256_accel.o");
+
+    run(Command::new(cc.as_slice())
+        .args(&["-O2", "-fPIC", "-c", "src/sha256_accel.c", "-o"])
+        .arg(&object));
+    run(Command::new(ar.as_slice())
+        .arg("crs")
+        .arg(out_dir.join("libreliable_rw_sha256.a"))
+        .arg(&object));
+
+    println!("cargo:rustc-flags=-L native={} -l reliable_rw_sha256:static", out_dir.display());
+}
@@ -0,0 +1,48 @@
+#!/bin/sh
+# Builds the fuzz targets into target/afl with AFL++ edge coverage.
+#
+# rustc 1.0.0-alpha's LLVM has no coverage instrumentation AFL can use, so
+# this has rustc emit bitcode, instruments it with a recent LLVM's `opt`
+# (which still reads bitcode this old), compiles that with its `llc`, and
+# links the result against AFL++'s runtime the way rustc would have.
+#
+#     OPT=opt LLC=llc AFL_RT=/path/to/afl-compiler-rt.o ./afl.sh
+#     afl-fuzz -i ../tests/golden -o findings -- target/afl/decode
+#
+# Checked with rustc 1.0.0-alpha, LLVM 14's `opt` and `llc`, and AFL++
+# 4.21c, whose afl-compiler-rt.o comes from instrumentation/ in its source.
+
+set -e
+
+: ${OPT:=opt} ${LLC:=llc} ${CC:=cc} ${AR:=ar}
+: ${AFL_RT:?"set AFL_RT to AFL++'s afl-compiler-rt.o"}
+
+cd "$(dirname "$0")"
+out="$PWD/target/afl"
+mkdir -p "$out"
+
+instrument() {
+    "$OPT" -passes=sancov-module -sanitizer-coverage-level=3 \
+        -sanitizer-coverage-trace-pc-guard "$1.bc" -o "$1.cov.bc"
+    "$LLC" -O2 -filetype=obj -relocation-model=pic "$1.cov.bc" -o "$1.cov.o"
+}
+
+"$CC" -O2 -fPIC -c ../src/sha256_accel.c -o "$out/sha256_accel.o"
+"$AR" crs "$out/libreliable_rw_sha256.a" "$out/sha256_accel.o"
+
+rustc ../src/reliable_rw.rs --crate-name reliable_rw --crate-type lib \
+    -C opt-level=3 --cfg ndebug --out-dir "$out" --emit=llvm-bc,link \
+    -L "native=$out" -l reliable_rw_sha256:static
+instrument "$out/reliable_rw"
+# Swap the instrumented object into the rlib the targets link against.
+cp "$out/reliable_rw.cov.o" "$out/reliable_rw.o"
+(cd "$out" && "$AR" r libreliable_rw.rlib reliable_rw.o)
+
+for target in decode mutate; do
+    link=$(rustc "fuzz_targets/$target.rs" --crate-name "$target" --crate-type bin \
+        -C opt-level=3 --cfg ndebug --out-dir "$out" --emit=llvm-bc,link \
+        --extern "reliable_rw=$out/libreliable_rw.rlib" \
+        -C "link-args=$AFL_RT" -Z print-link-args)
+    instrument "$out/$target"
+    eval "$(echo "$link" | sed "s|'$out/$target.o'|'$out/$target.cov.o'|")"
+done
@@ -0,0 +1,40 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Feeds raw fuzzer input, read from stdin, to the decoder.  There is no
+//! payload to compare against, so beyond not panicking this only checks
+//! that an accepted stream's digest is that of the payload it decoded to.
+
+extern crate reliable_rw;
+
+use std::io::{stdio, BufReader};
+use reliable_rw::{sha256_of, DecapOptions};
+
+mod harness;
+
+
+fn main() {
+    let data = stdio::stdin_raw().read_to_end().unwrap();
+    harness::abort_on_panic(move || {
+        for &strict in [true, false].iter() {
+            let mut input = BufReader::new(data.as_slice());
+            let mut output = Vec::new();
+            let result = match DecapOptions::new().strict(strict).decap(&mut input) {
+                Ok(mut decap) => decap.copy_to(&mut output),
+                Err(err) => Err(err)
+            };
+            match result {
+                Ok(digest) => {
+                    let expected = sha256_of(&mut BufReader::new(output.as_slice())).unwrap();
+                    assert!(digest.as_slice() == expected.as_slice());
+                },
+                Err(_) => ()
+            }
+        }
+    });
+}
@@ -0,0 +1,21 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! What the targets share: a panic is only a crash to AFL if the process
+//! dies of a signal, so it is turned into one.
+
+use std::thread::Thread;
+
+
+/// Runs `target`, aborting the process if it panics.
+pub fn abort_on_panic<F: FnOnce() + Send>(target: F) {
+    match Thread::scoped(target).join() {
+        Ok(()) => (),
+        Err(_) => unsafe { ::std::intrinsics::abort() }
+    }
+}
@@ -0,0 +1,26 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Uses fuzzer input, read from stdin, to steer mutations of valid
+//! streams, so the decoder is exercised past its digest checks and the
+//! payload of an accepted stream can be checked against what was encoded.
+
+extern crate reliable_rw;
+
+use std::io::stdio;
+use mutator::check_steered;
+
+mod harness;
+#[path = "../../tests/mutator/mod.rs"]
+mod mutator;
+
+
+fn main() {
+    let data = stdio::stdin_raw().read_to_end().unwrap();
+    harness::abort_on_panic(move || check_steered(data.as_slice()));
+}
@@ -0,0 +1,143 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The sending end's loop: encoding whatever a reader yields, and ending
+//! the stream so that the receiver only accepts a payload of the size that
+//! was declared.  `reliable-encap` and `reliable-send` both send through
+//! here.
+
+use libc;
+use std::io::{IoError, EndOfFile, File};
+use std::io::stdio::stdin_raw;
+use std::os::unix::AsRawFd;
+
+use timeout::TimeoutReader;
+use {EncapOptions, ReliableEncap, StreamHeader};
+
+
+/// Why a stream couldn't be sent in full.
+pub enum EncodeError {
+    /// The input couldn't be opened or described
+    Open(IoError),
+    /// Reading the input failed, or timed out
+    Read(IoError),
+    Write(IoError),
+    /// The payload wasn't the declared size, so the stream was left without
+    /// its final digest: (declared, read)
+    SizeChanged(u64, u64),
+}
+
+
+/// Encodes everything `input` yields as pieces, with a heartbeat whenever
+/// it is quiet for `heartbeat_ms`.  Ending the stream is left to the
+/// caller, unless the input runs past the `declared` size.
+pub fn encode<R: Reader>(input: &mut TimeoutReader<R>,
+                         encapper: &mut ReliableEncap,
+                         declared: Option<u64>,
+                         heartbeat_ms: Option<u32>) -> Result<(), EncodeError> {
+    loop {
+        match heartbeat_ms {
+            Some(ms) => match input.wait(Some(ms as u64)) {
+                Ok(true) => (),
+                Ok(false) => {
+                    match encapper.heartbeat(ms) {
+                        Ok(()) => continue,
+                        Err(err) => return Err(EncodeError::Write(err))
+                    }
+                },
+                Err(err) => return Err(EncodeError::Read(err))
+            },
+            None => ()
+        }
+        // Each read goes straight into the next piece's frame.
+        match encapper.fill_from(input) {
+            Ok(n) => {
+                // The receiver would only reject the payload, so stop before
+                // sending more than was declared, and end the stream as a
+                // failed producer's.
+                match declared {
+                    Some(size) if size - encapper.payload_length() < n as u64 => {
+                        let read = encapper.payload_length() + n as u64;
+                        return match encapper.finish_write() {
+                            Ok(()) => Err(EncodeError::SizeChanged(size, read)),
+                            Err(err) => Err(EncodeError::Write(err))
+                        };
+                    },
+                    _ => ()
+                }
+                match encapper.send_filled() {
+                    Ok(()) => (),
+                    Err(err) => return Err(EncodeError::Write(err))
+                }
+            },
+            Err(IoError { kind: EndOfFile, .. }) => return Ok(()),
+            Err(err) => return Err(EncodeError::Read(err))
+        };
+    }
+}
+
+
+/// Ends a stream whose payload was read successfully, and returns the
+/// payload's SHA-256 and size.  A payload of other than the `declared`
+/// size would be rejected, so it is sent without its final digest, as for
+/// a failed producer.
+pub fn finish(encapper: &mut ReliableEncap, declared: Option<u64>) -> Result<([u8; 32], u64), EncodeError> {
+    let sent = encapper.payload_length();
+    match declared {
+        Some(size) if size != sent => return match encapper.finish_write() {
+            Ok(()) => Err(EncodeError::SizeChanged(size, sent)),
+            Err(err) => Err(EncodeError::Write(err))
+        },
+        _ => ()
+    }
+    match encapper.finish_write().and_then(|()| encapper.finalize()) {
+        Ok(()) => Ok((encapper.sha256(), encapper.payload_length())),
+        Err(err) => Err(EncodeError::Write(err))
+    }
+}
+
+
+/// Sends the file at `path`, or stdin for `-`, as a whole stream with
+/// `options`, and returns the payload's SHA-256 and size.  A regular
+/// file's size, mode, mtime and holes are added to `header`.
+pub fn send_file(path: &str,
+                 options: &EncapOptions,
+                 header: StreamHeader,
+                 heartbeat_ms: Option<u32>,
+                 output: &mut Writer) -> Result<([u8; 32], u64), EncodeError> {
+    let mut header = header;
+    if path == "-" {
+        let declared = header.declared_size;
+        let mut input = TimeoutReader::new(stdin_raw(), libc::STDIN_FILENO, None, None);
+        let mut encapper = match options.clone().header(header).encap(output) {
+            Ok(encapper) => encapper,
+            Err(err) => return Err(EncodeError::Write(err))
+        };
+        try!(encode(&mut input, &mut encapper, declared, heartbeat_ms));
+        return finish(&mut encapper, declared);
+    }
+
+    let mut input = match File::open(&Path::new(path)) {
+        Ok(input) => input,
+        Err(err) => return Err(EncodeError::Open(err))
+    };
+    match header.describe_file(&mut input) {
+        Ok(()) => (),
+        Err(err) => return Err(EncodeError::Open(err))
+    }
+
+    let declared = header.declared_size;
+    let fd = input.as_raw_fd();
+    let mut input = TimeoutReader::new(input, fd, None, None);
+    let mut encapper = match options.clone().header(header).encap(output) {
+        Ok(encapper) => encapper,
+        Err(err) => return Err(EncodeError::Write(err))
+    };
+    try!(encode(&mut input, &mut encapper, declared, heartbeat_ms));
+    finish(&mut encapper, declared)
+}
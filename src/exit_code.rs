@@ -0,0 +1,78 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Exit statuses of `reliable-encap` and `reliable-write`.
+//!
+//! Both binaries share one table so that a pipeline's status can be
+//! interpreted without knowing which side produced it.  Every non-zero
+//! exit is accompanied by a one-line diagnostic on stderr.  Any other
+//! status (101 in particular) indicates a bug.
+
+/// The stream was written, or verified and committed.
+pub static SUCCESS: int = 0;
+
+/// Bad command line.
+pub static USAGE: int = 2;
+
+/// A digest in the stream didn't match its data.
+pub static INTEGRITY: int = 3;
+
+/// The stream was malformed or truncated.
+pub static PROTOCOL: int = 4;
+
+/// The producer (the child command of `reliable-encap`) couldn't be
+/// started or did not exit successfully.
+pub static PRODUCER_FAILED: int = 5;
+
+/// Reading input failed.
+pub static READ_IO: int = 6;
+
+/// Writing output failed.
+pub static WRITE_IO: int = 7;
+
+/// The verified output couldn't be moved into place.
+pub static COMMIT_FAILED: int = 8;
+
+/// The stream verified, but its payload wasn't the one we were told to
+/// expect (`--expect-sha256`, `--expect-size`).  Senders use it when what
+/// they read wasn't the size they declared.
+pub static UNEXPECTED_CONTENT: int = 9;
+
+/// The `--pre-commit` hook rejected the payload, or couldn't be run.
+/// Nothing was committed.
+pub static PRE_COMMIT_REJECTED: int = 10;
+
+/// The payload was committed, but the `--post-commit` hook failed or
+/// couldn't be run.
+pub static POST_COMMIT_FAILED: int = 11;
+
+/// Another writer held the target's lock (`--lock`) for longer than we
+/// were willing to wait.
+pub static LOCK_BUSY: int = 12;
+
+/// The target was not in the state `--if-match` or `--if-absent` required.
+/// Nothing was committed.
+pub static PRECONDITION_FAILED: int = 13;
+
+/// The declared payload size (plus `--reserve`) won't fit on the target's
+/// filesystem.  Nothing was read past the stream header.
+pub static NO_SPACE: int = 14;
+
+/// Reading timed out: the child command ran past `--timeout` or was silent
+/// for longer than `--idle-timeout`, or the stream stalled.  Nothing was
+/// committed.
+pub static TIMED_OUT: int = 15;
+
+/// The payload was larger than `--max-size`, or than the room left in the
+/// target directory's `--quota`.  Nothing was committed.
+pub static SIZE_LIMIT: int = 16;
+
+/// `reliable-encap --transport`: the receiver sent back no commit receipt,
+/// one we couldn't read, or, with `--receipt-key`, one which wasn't signed
+/// for this stream.  The payload may or may not have been committed.
+pub static NO_RECEIPT: int = 17;
@@ -0,0 +1,297 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Overlapping reads, hashing and writes.  `ReadAhead` reads its input on
+//! a thread of its own and `WriteBehind` writes its output on another, so
+//! the encoder or decoder between them only ever hashes.  The stages are
+//! joined by queues holding at most `depth` buffers each, and buffers go
+//! back to where they came from to be reused.
+//!
+//! None of this changes what a stream means: the decoder still checks
+//! every digest, and a payload is only good once `copy_to` has returned
+//! `Ok` and `WriteBehind::finish` has confirmed it reached the output.
+
+use std::io::{IoResult, IoError, OtherIoError};
+use std::slice::bytes::copy_memory;
+use std::sync::mpsc::{channel, sync_channel, Sender, Receiver, SyncSender};
+use std::thread::{Thread, JoinGuard};
+
+use {DecapOptions, ReliableWriteResult, ReliableWriteError};
+
+
+/// How many buffers each queue holds by default
+pub static DEFAULT_DEPTH: uint = 4;
+
+/// The size of the buffers the stages pass along
+pub static CHUNK_SIZE: uint = 64 * 1024;
+
+
+fn stage_died(desc: &'static str) -> IoError {
+    IoError { kind: OtherIoError, desc: desc, detail: None }
+}
+
+
+/// A reader whose input is read ahead on another thread, up to `depth`
+/// chunks in advance.  Errors, including the end of the input, arrive in
+/// order after the data read before them.
+pub struct ReadAhead {
+    filled: Receiver<IoResult<Vec<u8>>>,
+    /// Where spent buffers go back to the reading thread
+    spent: Sender<Vec<u8>>,
+    current: Vec<u8>,
+    /// How much of `current` has been read
+    offset: uint,
+    /// The error which ended the input, once it has arrived
+    ended: Option<IoError>,
+}
+
+
+impl ReadAhead {
+    pub fn new<R: Reader + Send + 'static>(input: R, depth: uint) -> ReadAhead {
+        let (filled_tx, filled_rx) = sync_channel(depth);
+        let (spent_tx, spent_rx) = channel();
+        Thread::spawn(move || read_ahead(input, depth, filled_tx, spent_rx));
+        ReadAhead {
+            filled: filled_rx,
+            spent: spent_tx,
+            current: Vec::new(),
+            offset: 0,
+            ended: None,
+        }
+    }
+}
+
+
+fn read_ahead<R: Reader>(mut input: R, depth: uint,
+                         filled: SyncSender<IoResult<Vec<u8>>>, spent: Receiver<Vec<u8>>) {
+    let mut allocated = 0;
+    loop {
+        let mut buf = if allocated < depth {
+            allocated += 1;
+            Vec::with_capacity(CHUNK_SIZE)
+        } else {
+            match spent.recv() {
+                Ok(buf) => buf,
+                // The reader is gone.
+                Err(_) => return
+            }
+        };
+        buf.clear();
+        let read = input.push(CHUNK_SIZE, &mut buf);
+        let failed = read.is_err();
+        if filled.send(read.map(move |_| buf)).is_err() || failed {
+            return;
+        }
+    }
+}
+
+
+impl Reader for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        while self.offset == self.current.len() {
+            match self.ended {
+                Some(ref err) => return Err(err.clone()),
+                None => ()
+            }
+            let next = match self.filled.recv() {
+                Ok(next) => next,
+                Err(_) => Err(stage_died("read-ahead thread died"))
+            };
+            match next {
+                Ok(next) => {
+                    let spent = ::std::mem::replace(&mut self.current, next);
+                    self.offset = 0;
+                    // The thread may have stopped at the end of its input.
+                    let _ = self.spent.send(spent);
+                },
+                Err(err) => self.ended = Some(err)
+            }
+        }
+        let n = ::std::cmp::min(buf.len(), self.current.len() - self.offset);
+        copy_memory(buf, self.current.slice(self.offset, self.offset + n));
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+
+enum WriteCommand {
+    Data(Vec<u8>),
+    Flush,
+}
+
+
+/// A writer which hands what it is given to another thread to write to
+/// `output`, in chunks, at most `depth` of them queued.  Errors from
+/// `output` are reported by a later write or flush, or by `finish`, which
+/// must be called to be sure everything was written.
+pub struct WriteBehind<W: Send + 'static> {
+    commands: Option<SyncSender<WriteCommand>>,
+    spent: Receiver<Vec<u8>>,
+    writer: Option<JoinGuard<'static, (W, IoResult<()>)>>,
+    depth: uint,
+    allocated: uint,
+    /// Data not yet handed over, gathered until it fills a chunk
+    current: Vec<u8>,
+    /// The output's error, once the writing thread has stopped on one
+    failed: Option<IoError>,
+}
+
+
+impl<W: Writer + Send + 'static> WriteBehind<W> {
+    pub fn new(output: W, depth: uint) -> WriteBehind<W> {
+        let (commands_tx, commands_rx) = sync_channel(depth);
+        let (spent_tx, spent_rx) = channel();
+        let writer = Thread::scoped(move || write_behind(output, commands_rx, spent_tx));
+        WriteBehind {
+            commands: Some(commands_tx),
+            spent: spent_rx,
+            writer: Some(writer),
+            depth: depth,
+            allocated: 1,
+            current: Vec::with_capacity(CHUNK_SIZE),
+            failed: None,
+        }
+    }
+
+    /// Hands over everything written, waits for it to be written and
+    /// flushed, and returns the output.
+    pub fn finish(mut self) -> IoResult<W> {
+        match self.failed {
+            Some(ref err) => return Err(err.clone()),
+            None => ()
+        }
+        try!(self.hand_over());
+        drop(self.commands.take());
+        let (output, written) = self.join();
+        try!(written);
+        Ok(output)
+    }
+
+    /// Waits for the writing thread to stop, which it does once the
+    /// command queue is closed or the output fails.
+    fn join(&mut self) -> (W, IoResult<()>) {
+        match self.writer.take().unwrap().join() {
+            Ok(result) => result,
+            Err(_) => panic!("write-behind thread panicked")
+        }
+    }
+
+    /// The error to report once the writing thread has given up.
+    fn stopped(&mut self) -> IoError {
+        if self.failed.is_none() {
+            drop(self.commands.take());
+            let (_, written) = self.join();
+            self.failed = Some(match written {
+                Err(err) => err,
+                Ok(()) => stage_died("write-behind thread stopped"),
+            });
+        }
+        self.failed.clone().unwrap()
+    }
+
+    fn send(&mut self, command: WriteCommand) -> IoResult<()> {
+        let sent = match self.commands {
+            Some(ref commands) => commands.send(command).is_ok(),
+            None => false
+        };
+        if sent { Ok(()) } else { Err(self.stopped()) }
+    }
+
+    /// Queues `current`, if it holds anything, and takes a fresh buffer.
+    fn hand_over(&mut self) -> IoResult<()> {
+        if self.current.len() == 0 {
+            return Ok(());
+        }
+        let next = match self.spent.try_recv() {
+            Ok(buf) => buf,
+            Err(_) if self.allocated < self.depth + 1 => {
+                self.allocated += 1;
+                Vec::with_capacity(CHUNK_SIZE)
+            },
+            Err(_) => match self.spent.recv() {
+                Ok(buf) => buf,
+                Err(_) => return Err(self.stopped())
+            }
+        };
+        let full = ::std::mem::replace(&mut self.current, next);
+        self.current.clear();
+        self.send(WriteCommand::Data(full))
+    }
+}
+
+
+fn write_behind<W: Writer>(mut output: W, commands: Receiver<WriteCommand>,
+                           spent: Sender<Vec<u8>>) -> (W, IoResult<()>) {
+    loop {
+        let written = match commands.recv() {
+            Ok(WriteCommand::Data(buf)) => {
+                let written = output.write(buf.as_slice());
+                let _ = spent.send(buf);
+                written
+            },
+            Ok(WriteCommand::Flush) => output.flush(),
+            // Closed by `finish`.
+            Err(_) => break
+        };
+        match written {
+            Ok(()) => (),
+            Err(err) => return (output, Err(err))
+        }
+    }
+    let flushed = output.flush();
+    (output, flushed)
+}
+
+
+impl<W: Writer + Send + 'static> Writer for WriteBehind<W> {
+    fn write(&mut self, mut buf: &[u8]) -> IoResult<()> {
+        match self.failed {
+            Some(ref err) => return Err(err.clone()),
+            None => ()
+        }
+        while buf.len() > 0 {
+            let room = CHUNK_SIZE - self.current.len();
+            let n = ::std::cmp::min(room, buf.len());
+            self.current.push_all(buf.slice_to(n));
+            buf = buf.slice_from(n);
+            if self.current.len() == CHUNK_SIZE {
+                try!(self.hand_over());
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands over what has been written and asks for it to be flushed,
+    /// without waiting for that to happen.
+    fn flush(&mut self) -> IoResult<()> {
+        try!(self.hand_over());
+        self.send(WriteCommand::Flush)
+    }
+}
+
+
+/// Like `copy_out`, with `options`, but with `input` read and `output`
+/// written on threads of their own.  Returns the SHA-256 of the payload
+/// and the output, once everything has been written to it.
+pub fn copy_out<R, W>(options: &DecapOptions, input: R, output: W) -> ReliableWriteResult<([u8; 32], W)>
+    where R: Reader + Send + 'static, W: Writer + Send + 'static
+{
+    let mut input = ReadAhead::new(input, DEFAULT_DEPTH);
+    let mut output = WriteBehind::new(output, DEFAULT_DEPTH);
+    let copied = match options.decap(&mut input) {
+        Ok(mut decap) => decap.copy_to(&mut output),
+        Err(err) => Err(err)
+    };
+    let digest = try!(copied);
+    match output.finish() {
+        Ok(output) => Ok((digest, output)),
+        Err(err) => Err(ReliableWriteError::WriteError(err))
+    }
+}
+
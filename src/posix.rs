@@ -0,0 +1,573 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Thin wrappers around the few POSIX calls the binaries need which
+//! `std::io` does not expose.
+//!
+//! Most of the constants, struct layouts and system calls here are Linux's.
+//! Elsewhere those wrappers fail with an error saying so, or fall back to
+//! doing nothing where that is already an outcome callers handle, so the
+//! library builds and the stream itself works anywhere.
+
+use libc::{c_int, c_char, c_short, pid_t, uid_t, size_t};
+#[cfg(target_os = "linux")]
+use libc::off_t;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::io::{IoResult, IoError, File};
+#[cfg(target_os = "linux")]
+use std::io::{Open, Write, PathAlreadyExists, MismatchedFileTypeForOperation, InvalidInput};
+#[cfg(target_os = "linux")]
+use std::num::NumCast;
+#[cfg(not(target_os = "linux"))]
+use std::io::OtherIoError;
+use std::os::errno;
+#[cfg(target_os = "linux")]
+use self::linux::*;
+
+pub use libc::consts::os::posix88::{SIGHUP, SIGINT, SIGPIPE, SIGTERM};
+
+// The same on every Unix
+const ESRCH: c_int = 3;
+const EINTR: c_int = 4;
+const SIG_DFL: size_t = 0;
+const POLLIN: c_short = 1;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use libc::{c_int, c_long};
+
+    pub const EWOULDBLOCK: c_int = 11;
+    pub const ENXIO: c_int = 6;
+    pub const EINVAL: c_int = 22;
+    pub const ENOSPC: c_int = 28;
+    pub const EOPNOTSUPP: c_int = 95;
+
+    pub const O_RDONLY: c_int = 0;
+    pub const O_WRONLY: c_int = 1;
+    pub const O_RDWR: c_int = 2;
+    pub const O_CREAT: c_int = 0o100;
+    pub const O_EXCL: c_int = 0o200;
+    pub const O_CLOEXEC: c_int = 0o2000000;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub const O_NOFOLLOW: c_int = 0o400000;
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub const O_NOFOLLOW: c_int = 0o100000;
+
+    pub const LOCK_EX: c_int = 2;
+    pub const LOCK_NB: c_int = 4;
+
+    pub const FALLOC_FL_KEEP_SIZE: c_int = 1;
+
+    pub const SEEK_SET: c_int = 0;
+    pub const SEEK_DATA: c_int = 3;
+    pub const SEEK_HOLE: c_int = 4;
+
+    pub const RUSAGE_CHILDREN: c_int = -1;
+
+    pub const CLOCK_MONOTONIC: c_int = 1;
+
+    pub const S_IFMT: u32 = 0o170000;
+    pub const S_IFDIR: u32 = 0o040000;
+    pub const S_IFREG: u32 = 0o100000;
+
+    pub const AT_FDCWD: c_int = -100;
+    pub const RENAME_NOREPLACE: c_int = 1 << 0;
+    pub const RENAME_EXCHANGE: c_int = 1 << 1;
+
+    #[cfg(target_arch = "x86_64")]
+    pub const SYS_RENAMEAT2: c_long = 316;
+    #[cfg(target_arch = "x86")]
+    pub const SYS_RENAMEAT2: c_long = 353;
+    #[cfg(target_arch = "aarch64")]
+    pub const SYS_RENAMEAT2: c_long = 276;
+    #[cfg(target_arch = "arm")]
+    pub const SYS_RENAMEAT2: c_long = 382;
+}
+
+/// Signature of a handler installed with `set_signal_handler`.
+pub type SignalHandler = extern "C" fn(c_int);
+
+mod ffi {
+    use libc::{c_int, c_char, c_short, c_ulong, pid_t, uid_t, mode_t, size_t, time_t};
+    #[cfg(target_os = "linux")]
+    use libc::{c_long, off_t};
+
+    #[repr(C)]
+    pub struct pollfd {
+        pub fd: c_int,
+        pub events: c_short,
+        pub revents: c_short,
+    }
+
+    #[cfg(target_os = "linux")]
+    #[repr(C)]
+    pub struct timespec {
+        pub tv_sec: time_t,
+        pub tv_nsec: c_long,
+    }
+
+    #[cfg(target_os = "linux")]
+    #[repr(C)]
+    pub struct timeval {
+        pub tv_sec: c_long,
+        pub tv_usec: c_long,
+    }
+
+    #[cfg(target_os = "linux")]
+    #[repr(C)]
+    pub struct rusage {
+        pub ru_utime: timeval,
+        pub ru_stime: timeval,
+        pub ru_maxrss: c_long,
+        pub ru_ixrss: c_long,
+        pub ru_idrss: c_long,
+        pub ru_isrss: c_long,
+        pub ru_minflt: c_long,
+        pub ru_majflt: c_long,
+        pub ru_nswap: c_long,
+        pub ru_inblock: c_long,
+        pub ru_oublock: c_long,
+        pub ru_msgsnd: c_long,
+        pub ru_msgrcv: c_long,
+        pub ru_nsignals: c_long,
+        pub ru_nvcsw: c_long,
+        pub ru_nivcsw: c_long,
+    }
+
+    #[cfg(target_os = "linux")]
+    #[repr(C)]
+    pub struct statvfs {
+        pub f_bsize: c_ulong,
+        pub f_frsize: c_ulong,
+        pub f_blocks: c_ulong,
+        pub f_bfree: c_ulong,
+        pub f_bavail: c_ulong,
+        pub f_files: c_ulong,
+        pub f_ffree: c_ulong,
+        pub f_favail: c_ulong,
+        pub f_fsid: c_ulong,
+        #[cfg(target_word_size = "32")]
+        pub __f_unused: c_int,
+        pub f_flag: c_ulong,
+        pub f_namemax: c_ulong,
+        pub __f_spare: [c_int; 6],
+    }
+
+    extern {
+        pub fn time(t: *mut time_t) -> time_t;
+        pub fn poll(fds: *mut pollfd, nfds: c_ulong, timeout: c_int) -> c_int;
+        pub fn close(fd: c_int) -> c_int;
+        pub fn signal(signum: c_int, handler: size_t) -> size_t;
+        pub fn raise(signum: c_int) -> c_int;
+        pub fn kill(pid: pid_t, sig: c_int) -> c_int;
+        pub fn getpid() -> pid_t;
+        pub fn getuid() -> uid_t;
+        pub fn unlink(path: *const c_char) -> c_int;
+        pub fn umask(mask: mode_t) -> mode_t;
+    }
+
+    #[cfg(target_os = "linux")]
+    extern {
+        pub fn clock_gettime(clock: c_int, tp: *mut timespec) -> c_int;
+        pub fn getrusage(who: c_int, usage: *mut rusage) -> c_int;
+        pub fn statvfs(path: *const c_char, buf: *mut statvfs) -> c_int;
+        pub fn fallocate(fd: c_int, mode: c_int, offset: off_t, len: off_t) -> c_int;
+        pub fn lseek(fd: c_int, offset: off_t, whence: c_int) -> off_t;
+        pub fn open(path: *const c_char, flags: c_int, mode: mode_t) -> c_int;
+        pub fn flock(fd: c_int, operation: c_int) -> c_int;
+        pub fn syscall(number: c_long, ...) -> c_long;
+    }
+}
+
+/// The error the Linux-only wrappers fail with elsewhere.
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> IoError {
+    IoError { kind: OtherIoError, desc: "not supported on this platform", detail: None }
+}
+
+/// The process id of the calling process.
+pub fn getpid() -> pid_t {
+    unsafe { ffi::getpid() }
+}
+
+/// The real user id of the calling process.
+pub fn getuid() -> uid_t {
+    unsafe { ffi::getuid() }
+}
+
+/// Seconds since the epoch.
+pub fn unix_time() -> i64 {
+    unsafe { ffi::time(0 as *mut _) as i64 }
+}
+
+/// The file mode creation mask.  Reading it means setting it, so this
+/// must not race with another thread creating files.
+pub fn umask() -> u32 {
+    unsafe {
+        let mask = ffi::umask(0o077);
+        ffi::umask(mask);
+        mask as u32
+    }
+}
+
+/// Milliseconds on a clock which only ever moves forwards, from some
+/// arbitrary starting point.  For measuring timeouts.
+#[cfg(target_os = "linux")]
+pub fn monotonic_ms() -> u64 {
+    let mut ts = ffi::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { ffi::clock_gettime(CLOCK_MONOTONIC, &mut ts); }
+    ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
+}
+
+/// Elsewhere, the time of day, to the second, which will do for timeouts
+/// unless the clock is changed.
+#[cfg(not(target_os = "linux"))]
+pub fn monotonic_ms() -> u64 {
+    unix_time() as u64 * 1000
+}
+
+/// Waits up to `timeout_ms` for `fd` to become readable, which includes
+/// reaching end of file or an error.  Returns whether it did.
+pub fn wait_readable(fd: c_int, timeout_ms: u64) -> IoResult<bool> {
+    let mut pfd = ffi::pollfd { fd: fd, events: POLLIN, revents: 0 };
+    let timeout = ::std::cmp::min(timeout_ms, ::std::i32::MAX as u64) as c_int;
+    loop {
+        match unsafe { ffi::poll(&mut pfd, 1, timeout) } {
+            -1 if errno() as c_int == EINTR => continue,
+            -1 => return Err(IoError::last_error()),
+            0 => return Ok(false),
+            _ => return Ok(true)
+        }
+    }
+}
+
+/// Whether a process with the given pid currently exists.  A process we
+/// aren't allowed to signal still counts as existing.
+pub fn process_exists(pid: pid_t) -> bool {
+    unsafe { ffi::kill(pid, 0) == 0 || errno() as c_int != ESRCH }
+}
+
+/// Installs `handler` for `signum`.
+pub fn set_signal_handler(signum: c_int, handler: SignalHandler) {
+    unsafe { ffi::signal(signum, handler as size_t); }
+}
+
+/// Restores the default disposition of `signum` and raises it again, so
+/// the process dies the way it would have without our handler.  Only
+/// async-signal-safe calls are made, so this may be used from a handler.
+pub fn reraise(signum: c_int) {
+    unsafe {
+        ffi::signal(signum, SIG_DFL);
+        ffi::raise(signum);
+    }
+}
+
+/// `unlink(2)` on a NUL-terminated path.  Async-signal-safe, so usable
+/// from a signal handler where allocating a `Path` is not.
+pub unsafe fn unlink_raw(path: *const c_char) -> bool {
+    ffi::unlink(path) == 0
+}
+
+/// Creates `path` as a new, empty file and opens it for writing.  Fails
+/// with `PathAlreadyExists` if anything is already there, and never
+/// follows a symlink, even a dangling one.  `std::io` can't make a `File`
+/// from a descriptor, so `path` is opened again and checked to be the
+/// file just created before it is returned.
+#[cfg(target_os = "linux")]
+pub fn create_new(path: &Path) -> IoResult<File> {
+    let c_path = CString::from_slice(path.as_vec());
+    let flags = O_WRONLY | O_CREAT | O_EXCL | O_NOFOLLOW | O_CLOEXEC;
+    let fd = match unsafe { ffi::open(c_path.as_ptr(), flags, 0o666) } {
+        -1 => return Err(IoError::last_error()),
+        fd => fd
+    };
+    let mut created: ::libc::stat = unsafe { ::std::mem::zeroed() };
+    let file = match unsafe { ::libc::fstat(fd, &mut created) } {
+        0 => File::open_mode(path, Open, Write),
+        _ => Err(IoError::last_error())
+    };
+    unsafe { ffi::close(fd); }
+    let file = try!(file);
+    let opened = try!(file.stat());
+    if opened.unstable.device != created.st_dev as u64 || opened.unstable.inode != created.st_ino as u64 {
+        return Err(IoError {
+            kind: PathAlreadyExists,
+            desc: "file replaced while being created",
+            detail: None,
+        });
+    }
+    Ok(file)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn create_new(_path: &Path) -> IoResult<File> {
+    Err(unsupported())
+}
+
+#[cfg(target_os = "linux")]
+fn renameat2(from: &Path, to: &Path, flags: c_int) -> IoResult<()> {
+    let from = CString::from_slice(from.as_vec());
+    let to = CString::from_slice(to.as_vec());
+    let rv = unsafe {
+        ffi::syscall(SYS_RENAMEAT2,
+                     AT_FDCWD, from.as_ptr(), AT_FDCWD, to.as_ptr(), flags)
+    };
+    match rv {
+        0 => Ok(()),
+        _ => Err(IoError::last_error())
+    }
+}
+
+/// Atomically swaps the names of `a` and `b`, both of which must exist.
+/// They may be directories.  Needs Linux 3.15 and a filesystem which
+/// supports `RENAME_EXCHANGE`.
+#[cfg(target_os = "linux")]
+pub fn rename_exchange(a: &Path, b: &Path) -> IoResult<()> {
+    renameat2(a, b, RENAME_EXCHANGE)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn rename_exchange(_a: &Path, _b: &Path) -> IoResult<()> {
+    Err(unsupported())
+}
+
+/// Renames `from` to `to`, failing rather than replacing `to` if it
+/// already exists.
+#[cfg(target_os = "linux")]
+pub fn rename_noreplace(from: &Path, to: &Path) -> IoResult<()> {
+    renameat2(from, to, RENAME_NOREPLACE)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn rename_noreplace(_from: &Path, _to: &Path) -> IoResult<()> {
+    Err(unsupported())
+}
+
+/// An exclusive `flock(2)` lock.  It is released when the `FileLock` is
+/// dropped or, failing that, when the process exits.  The descriptor is
+/// close-on-exec, so child processes never hold on to it.
+pub struct FileLock {
+    fd: c_int,
+}
+
+impl FileLock {
+    /// Opens `path` for locking without locking it yet.  Unless `path` is
+    /// a directory, it is created as an empty file if missing, and a
+    /// symlink there is refused rather than followed.  Fails if `path`
+    /// turns out not to be a regular file, or a directory if `is_dir`.
+    #[cfg(target_os = "linux")]
+    pub fn open(path: &Path, is_dir: bool) -> IoResult<FileLock> {
+        // The directory is only read, so it may be reached through a
+        // symlink, as a target's parent often is.
+        let (flags, kind) = if is_dir {
+            (O_RDONLY | O_CLOEXEC, S_IFDIR)
+        } else {
+            (O_RDWR | O_CREAT | O_NOFOLLOW | O_CLOEXEC, S_IFREG)
+        };
+        let path = CString::from_slice(path.as_vec());
+        let lock = match unsafe { ffi::open(path.as_ptr(), flags, 0o666) } {
+            -1 => return Err(IoError::last_error()),
+            fd => FileLock { fd: fd }
+        };
+        let mut stat: ::libc::stat = unsafe { ::std::mem::zeroed() };
+        if unsafe { ::libc::fstat(lock.fd, &mut stat) } != 0 {
+            return Err(IoError::last_error());
+        }
+        if stat.st_mode as u32 & S_IFMT != kind {
+            return Err(IoError {
+                kind: MismatchedFileTypeForOperation,
+                desc: "lock path is not a regular file or directory",
+                detail: None,
+            });
+        }
+        Ok(lock)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_path: &Path, _is_dir: bool) -> IoResult<FileLock> {
+        Err(unsupported())
+    }
+
+    /// Blocks until we hold the lock.
+    #[cfg(target_os = "linux")]
+    pub fn lock(&self) -> IoResult<()> {
+        loop {
+            match unsafe { ffi::flock(self.fd, LOCK_EX) } {
+                0 => return Ok(()),
+                _ if errno() as c_int == EINTR => continue,
+                _ => return Err(IoError::last_error())
+            }
+        }
+    }
+
+    /// Takes the lock if nobody else holds it.  Returns whether we got it.
+    #[cfg(target_os = "linux")]
+    pub fn try_lock(&self) -> IoResult<bool> {
+        match unsafe { ffi::flock(self.fd, LOCK_EX | LOCK_NB) } {
+            0 => Ok(true),
+            _ if errno() as c_int == EWOULDBLOCK => Ok(false),
+            _ => Err(IoError::last_error())
+        }
+    }
+
+    // Never reached elsewhere, as there is no way to open one.
+    #[cfg(not(target_os = "linux"))]
+    pub fn lock(&self) -> IoResult<()> {
+        Err(unsupported())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn try_lock(&self) -> IoResult<bool> {
+        Err(unsupported())
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        unsafe { ffi::close(self.fd); }
+    }
+}
+
+/// The number of bytes unprivileged users may still allocate on the
+/// filesystem containing `path`.
+#[cfg(target_os = "linux")]
+pub fn available_space(path: &Path) -> IoResult<u64> {
+    let path = CString::from_slice(path.as_vec());
+    let mut buf: ffi::statvfs = unsafe { ::std::mem::zeroed() };
+    match unsafe { ffi::statvfs(path.as_ptr(), &mut buf) } {
+        0 => Ok(buf.f_bavail as u64 * buf.f_frsize as u64),
+        _ => Err(IoError::last_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn available_space(_path: &Path) -> IoResult<u64> {
+    Err(unsupported())
+}
+
+/// The outcome of `preallocate`.
+#[derive(Clone, Copy, PartialEq, Show)]
+pub enum Preallocation {
+    Allocated,
+    /// The filesystem can't preallocate; nothing was done
+    Unsupported,
+    /// There isn't enough space
+    NoSpace,
+}
+
+/// `n` as an `off_t`, which is signed, and only 32 bits wide on some
+/// targets.
+#[cfg(target_os = "linux")]
+fn to_off_t(n: u64) -> IoResult<off_t> {
+    match NumCast::from(n) {
+        Some(n) => Ok(n),
+        None => Err(IoError {
+            kind: InvalidInput,
+            desc: "size too large for this platform's off_t",
+            detail: Some(n.to_string()),
+        })
+    }
+}
+
+/// Reserves `len` bytes of disk for the open file `fd`, without changing
+/// its size, so running out of space shows up now rather than part way
+/// through writing.
+#[cfg(target_os = "linux")]
+pub fn preallocate(fd: c_int, len: u64) -> IoResult<Preallocation> {
+    if len == 0 {
+        return Ok(Preallocation::Allocated);
+    }
+    let len = try!(to_off_t(len));
+    match unsafe { ffi::fallocate(fd, FALLOC_FL_KEEP_SIZE, 0, len) } {
+        0 => Ok(Preallocation::Allocated),
+        _ => match errno() as c_int {
+            ENOSPC => Ok(Preallocation::NoSpace),
+            EOPNOTSUPP => Ok(Preallocation::Unsupported),
+            _ => Err(IoError::last_error())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(_fd: c_int, _len: u64) -> IoResult<Preallocation> {
+    Ok(Preallocation::Unsupported)
+}
+
+/// The holes in the first `size` bytes of the open file `fd`, as
+/// (offset, length) pairs, found with `SEEK_DATA` and `SEEK_HOLE`.  A
+/// filesystem which doesn't support those reports no holes.  Leaves the
+/// file positioned at its start.
+#[cfg(target_os = "linux")]
+pub fn holes(fd: c_int, size: u64) -> IoResult<Vec<(u64, u64)>> {
+    let mut holes = Vec::new();
+    let mut pos = 0;
+    while pos < size {
+        let data = match unsafe { ffi::lseek(fd, pos as off_t, SEEK_DATA) } {
+            -1 => match errno() as c_int {
+                // No data past `pos`: the rest of the file is a hole.
+                ENXIO => size,
+                EINVAL => return Ok(Vec::new()),
+                _ => return Err(IoError::last_error())
+            },
+            data => data as u64
+        };
+        if size <= data {
+            holes.push((pos, size - pos));
+            break;
+        }
+        if pos < data {
+            holes.push((pos, data - pos));
+        }
+        pos = match unsafe { ffi::lseek(fd, data as off_t, SEEK_HOLE) } {
+            -1 => return Err(IoError::last_error()),
+            hole => hole as u64
+        };
+    }
+    match unsafe { ffi::lseek(fd, 0, SEEK_SET) } {
+        -1 => Err(IoError::last_error()),
+        _ => Ok(holes)
+    }
+}
+
+/// Elsewhere, as on a filesystem without `SEEK_DATA`, no holes are found
+/// and the file is sent as it reads.
+#[cfg(not(target_os = "linux"))]
+pub fn holes(_fd: c_int, _size: u64) -> IoResult<Vec<(u64, u64)>> {
+    Ok(Vec::new())
+}
+
+
+/// CPU time and memory used by a process.
+#[derive(Clone, Copy, Default, Show)]
+pub struct ResourceUsage {
+    pub user_us: u64,
+    pub system_us: u64,
+    /// Peak resident set size, in KiB
+    pub max_rss_kb: u64,
+}
+
+/// The resources used by all children of this process which have been
+/// waited for.  The peak RSS is that of the largest one rather than a sum.
+#[cfg(target_os = "linux")]
+pub fn children_usage() -> IoResult<ResourceUsage> {
+    let mut buf: ffi::rusage = unsafe { ::std::mem::zeroed() };
+    match unsafe { ffi::getrusage(RUSAGE_CHILDREN, &mut buf) } {
+        0 => Ok(ResourceUsage {
+            user_us: buf.ru_utime.tv_sec as u64 * 1_000_000 + buf.ru_utime.tv_usec as u64,
+            system_us: buf.ru_stime.tv_sec as u64 * 1_000_000 + buf.ru_stime.tv_usec as u64,
+            max_rss_kb: buf.ru_maxrss as u64,
+        }),
+        _ => Err(IoError::last_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn children_usage() -> IoResult<ResourceUsage> {
+    Err(unsupported())
+}
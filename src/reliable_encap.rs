@@ -1,5 +1,4 @@
 #![feature(macro_rules)]
-#![allow(unused_must_use)]
 // Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
 // 
 // Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
@@ -11,99 +10,553 @@
 extern crate libc;
 extern crate reliable_rw;
 
+use std::fmt;
+use std::default::Default;
 use std::os::{args, set_exit_status};
-use std::io::{stdout, stderr, Command, IoError, EndOfFile};
-use std::io::process::{InheritFd, ExitStatus, ExitSignal};
-use reliable_rw::ReliableEncap;
+use std::io::{stderr, Command, IoError, TimedOut};
+use std::io::stdio::stdout_raw;
+use std::io::process::{Process, InheritFd, ProcessExit, ExitStatus, ExitSignal};
+use std::os::unix::AsRawFd;
+use std::cmp::min;
+use std::num::Int;
+use std::io::timer::sleep;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::Duration;
+use std::thread::Thread;
+use reliable_rw::{exit_code, pipeline, posix, StreamHeader, ProducerStatus};
+use reliable_rw::{EncapOptions, FlushPolicy, ReceiptKey, RECORD_FLAG};
+use reliable_rw::encode::{encode, finish, send_file, EncodeError};
+use reliable_rw::pipeline::WriteBehind;
+use reliable_rw::transport::{Transport, Delivery, SendFailure};
+use reliable_rw::timeout::TimeoutReader;
 
 
-pub static PIECE_SIZE: uint = 32 * 1024;  // 32kB
+/// Why a run ended without a complete stream.
+enum Failure {
+    Usage,
+    Open(IoError),
+    Spawn(IoError),
+    Read(IoError),
+    Write(IoError),
+    Producer(ProcessExit),
+    /// The child was killed for running too long, for the given reason
+    TimedOut(&'static str),
+    Wait(IoError),
+    /// The payload wasn't the declared size: (declared, sent)
+    SizeChanged(u64, u64),
+    /// `--transport` couldn't be started
+    Transport(IoError),
+    /// `--receipt-key` couldn't be read, or no nonce made for it
+    ReceiptKey(IoError),
+    /// The receiver didn't confirm it committed what we sent
+    Delivery(Delivery),
+}
 
 
-fn print_usage(program: &str) {
-    println!("{} [--] command", program);
+impl Failure {
+    fn exit_code(&self) -> int {
+        match *self {
+            Failure::Usage => exit_code::USAGE,
+            Failure::Open(_) => exit_code::READ_IO,
+            Failure::Spawn(_) => exit_code::PRODUCER_FAILED,
+            Failure::Read(_) => exit_code::READ_IO,
+            Failure::Write(_) => exit_code::WRITE_IO,
+            Failure::Producer(_) => exit_code::PRODUCER_FAILED,
+            Failure::TimedOut(_) => exit_code::TIMED_OUT,
+            Failure::Wait(_) => exit_code::PRODUCER_FAILED,
+            Failure::SizeChanged(..) => exit_code::UNEXPECTED_CONTENT,
+            Failure::Transport(_) => exit_code::WRITE_IO,
+            Failure::ReceiptKey(_) => exit_code::READ_IO,
+            Failure::Delivery(ref delivery) => delivery.exit_code(),
+        }
+    }
 }
 
 
-fn main() {
-    let args = args();
-    let program_name = args[0].as_slice().clone();
-    if args.len() < 2 {
-        print_usage(program_name);
-        set_exit_status(1);
-        return;
+impl fmt::String for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Usage => write!(f, "usage error"),
+            Failure::Open(ref err) => write!(f, "could not open input: {}", err),
+            Failure::Spawn(ref err) => write!(f, "failed to execute process: {}", err),
+            Failure::Read(ref err) => write!(f, "error reading from process: {}", err),
+            Failure::Write(ref err) => write!(f, "error writing stream: {}", err),
+            Failure::Producer(ExitStatus(n)) => write!(f, "process exited with status {}", n),
+            Failure::Producer(ExitSignal(n)) => write!(f, "process killed by signal {}", n),
+            Failure::TimedOut(why) => write!(f, "process killed: {}", why),
+            Failure::Wait(ref err) => write!(f, "error waiting for process: {}", err),
+            Failure::SizeChanged(declared, sent) =>
+                write!(f, "declared {} bytes but read {}, so the stream was left unfinished", declared, sent),
+            Failure::Transport(ref err) => write!(f, "could not run transport: {}", err),
+            Failure::ReceiptKey(ref err) => write!(f, "could not set up receipt key: {}", err),
+            Failure::Delivery(ref delivery) => write!(f, "{}", delivery),
+        }
     }
+}
+
+
+fn print_usage(program: &str) {
+    // Our stdout may be the stream, so none of this goes there.
+    let mut stderr = stderr();
+    let _ = writeln!(&mut stderr, "{} [stream options] [--send-status] [--stderr-tail BYTES]", program);
+    let _ = writeln!(&mut stderr, "    [--timeout SECONDS] [--idle-timeout SECONDS] [--] command");
+    let _ = writeln!(&mut stderr, "{} [stream options] --file PATH", program);
+    let _ = writeln!(&mut stderr, "");
+    let _ = writeln!(&mut stderr, "Either form may also be given --transport CMD, to run the stream through");
+    let _ = writeln!(&mut stderr, "`sh -c CMD` and wait for a commit receipt from `reliable-write --receipt`,");
+    let _ = writeln!(&mut stderr, "and then --receipt-key FILE to only accept a receipt signed with the key in");
+    let _ = writeln!(&mut stderr, "FILE, which the receiver must be given too.");
+    let _ = writeln!(&mut stderr, "");
+    let _ = writeln!(&mut stderr, "stream options:");
+    let _ = writeln!(&mut stderr, "  --declared-size BYTES  promise a payload of exactly this size");
+    let _ = writeln!(&mut stderr, "  --heartbeat SECONDS    send a heartbeat when the input is quiet this long");
+    let _ = writeln!(&mut stderr, "  --piece-size BYTES     send pieces of up to this size (default 32768)");
+    let _ = writeln!(&mut stderr, "  --flush-each-piece     flush the output after every piece");
+    let _ = writeln!(&mut stderr, "  --pipeline             write the stream on a separate thread");
+}
+
+
+struct Options {
+    encap: EncapOptions,
+    header: StreamHeader,
+    /// `--send-status`: say why the command failed, in the stream
+    send_status: bool,
+    /// `--stderr-tail`: how much of the command's stderr to send with its
+    /// status
+    stderr_tail: uint,
+    /// `--timeout`: how long the command may run, in milliseconds
+    timeout_ms: Option<u64>,
+    /// `--idle-timeout`: how long the command may go without output
+    idle_timeout_ms: Option<u64>,
+    /// `--heartbeat`: how long to stay quiet before sending a heartbeat.
+    /// Never zero, and sent in the heartbeat as a u32.
+    heartbeat_ms: Option<u32>,
+    /// `--transport`: a shell command to send the stream through
+    transport: Option<String>,
+    /// `--receipt-key`: the file holding the key the receipt must be
+    /// signed with
+    receipt_key: Option<Path>,
+    /// `--pipeline`: write the stream on another thread
+    pipeline: bool,
+    source: Source,
+}
+
+
+/// Where the payload comes from.
+enum Source {
+    /// `--file PATH`, or stdin for `-`
+    File(String),
+    /// The stdout of a child command
+    Command(Vec<String>),
+}
 
+
+fn parse_args(args: &[String]) -> Option<Options> {
     let mut cmd_args: &[String] = args.tail();
+    let mut encap = EncapOptions::new();
+    let mut header: StreamHeader = Default::default();
+    let mut send_status = false;
+    let mut stderr_tail = 0;
+    let mut timeout_ms = None;
+    let mut idle_timeout_ms = None;
+    let mut heartbeat_ms = None;
+    let mut transport = None;
+    let mut receipt_key = None;
+    let mut pipeline = false;
+    let mut file = None;
 
-    let head = cmd_args.get(0);
-    if head.is_some() && head.unwrap().as_slice() == "--" {
-        cmd_args = cmd_args.tail();
-    } else {
-        let mut stderr = stderr();
-        let warning = "Warning: please include -- before the command name\n";
-        assert!(stderr.write(warning.as_bytes()).is_ok());
+    loop {
+        let head = cmd_args.get(0).map(|arg| arg.as_slice());
+        if head == Some("--declared-size") {
+            header.declared_size = match cmd_args.get(1).and_then(|v| v.parse::<u64>()) {
+                Some(size) => Some(size),
+                None => return None
+            };
+            cmd_args = cmd_args.slice_from(2);
+        } else if head == Some("--piece-size") {
+            match cmd_args.get(1).and_then(|v| v.parse::<uint>()) {
+                Some(size) if 0 < size && size < RECORD_FLAG as uint => encap.piece_size(size),
+                _ => return None
+            };
+            cmd_args = cmd_args.slice_from(2);
+        } else if head == Some("--flush-each-piece") {
+            encap.flush(FlushPolicy::EachPiece);
+            cmd_args = cmd_args.tail();
+        } else if head == Some("--pipeline") {
+            pipeline = true;
+            cmd_args = cmd_args.tail();
+        } else if head == Some("--send-status") {
+            send_status = true;
+            cmd_args = cmd_args.tail();
+        } else if head == Some("--stderr-tail") {
+            stderr_tail = match cmd_args.get(1).and_then(|v| v.parse::<uint>()) {
+                Some(bytes) => bytes,
+                None => return None
+            };
+            send_status = true;
+            cmd_args = cmd_args.slice_from(2);
+        } else if head == Some("--timeout") || head == Some("--idle-timeout") || head == Some("--heartbeat") {
+            let ms = match cmd_args.get(1).and_then(|v| v.parse::<u64>()) {
+                Some(secs) => match secs.checked_mul(1000) {
+                    Some(ms) => ms,
+                    None => return None
+                },
+                None => return None
+            };
+            if head == Some("--timeout") {
+                timeout_ms = Some(ms);
+            } else if head == Some("--idle-timeout") {
+                idle_timeout_ms = Some(ms);
+            } else if 0 < ms && ms <= std::u32::MAX as u64 {
+                // With no interval, we'd send nothing but heartbeats.
+                heartbeat_ms = Some(ms as u32);
+            } else {
+                return None;
+            }
+            cmd_args = cmd_args.slice_from(2);
+        } else if head == Some("--transport") {
+            transport = match cmd_args.get(1) {
+                Some(cmd) => Some(cmd.clone()),
+                None => return None
+            };
+            cmd_args = cmd_args.slice_from(2);
+        } else if head == Some("--receipt-key") {
+            receipt_key = match cmd_args.get(1) {
+                Some(path) => Some(Path::new(path.as_slice())),
+                None => return None
+            };
+            cmd_args = cmd_args.slice_from(2);
+        } else if head == Some("--file") {
+            file = match cmd_args.get(1) {
+                Some(path) => Some(path.clone()),
+                None => return None
+            };
+            cmd_args = cmd_args.slice_from(2);
+        } else if head == Some("--") {
+            cmd_args = cmd_args.tail();
+            break;
+        } else {
+            if file.is_none() && head.is_some() {
+                let mut stderr = stderr();
+                let warning = "Warning: please include -- before the command name\n";
+                let _ = stderr.write(warning.as_bytes());
+            }
+            break;
+        }
+    }
+
+    // Only a transport brings a receipt back.
+    if receipt_key.is_some() && transport.is_none() {
+        return None;
+    }
+    let source = match file {
+        Some(_) if cmd_args.len() > 0 => return None,
+        Some(path) => Source::File(path),
+        None if cmd_args.len() == 0 => return None,
+        None => Source::Command(cmd_args.to_vec()),
+    };
+    Some(Options {
+        encap: encap,
+        header: header,
+        send_status: send_status,
+        stderr_tail: stderr_tail,
+        timeout_ms: timeout_ms,
+        idle_timeout_ms: idle_timeout_ms,
+        heartbeat_ms: heartbeat_ms,
+        transport: transport,
+        receipt_key: receipt_key,
+        pipeline: pipeline,
+        source: source,
+    })
+}
+
+
+fn read_failure(err: IoError) -> Failure {
+    match err.kind {
+        TimedOut => Failure::TimedOut(err.desc),
+        _ => Failure::Read(err)
+    }
+}
+
+
+/// The failure to report for what stopped a stream being sent.
+fn encode_failure(err: EncodeError) -> Failure {
+    match err {
+        EncodeError::Open(err) => Failure::Open(err),
+        EncodeError::Read(err) => read_failure(err),
+        EncodeError::Write(err) => Failure::Write(err),
+        EncodeError::SizeChanged(declared, sent) => Failure::SizeChanged(declared, sent),
+    }
+}
+
+
+/// How long to wait, once the child has exited, for its stderr to end.
+/// Something it started may still hold the pipe open.
+static STDERR_GRACE_MS: u64 = 1000;
+
+
+/// Copies `input` to our stderr as it arrives, keeping at least the last
+/// `limit` bytes of it in `tail`, until it ends.
+fn keep_tail(input: &mut Reader, limit: uint, tail: &Mutex<Vec<u8>>) {
+    let mut stderr = stderr();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match input.read(buf.as_mut_slice()) {
+            Ok(n) => n,
+            Err(_) => return
+        };
+        let _ = stderr.write(buf.slice_to(n));
+        let mut tail = tail.lock().unwrap();
+        tail.push_all(buf.slice_to(n));
+        // Trim only now and then, rather than on every read.
+        if 2 * limit < tail.len() {
+            let start = tail.len() - limit;
+            *tail = tail.slice_from(start).to_vec();
+        }
     }
+}
+
+
+/// The last `limit` bytes of the child's stderr, once `ended` says it has
+/// ended or `STDERR_GRACE_MS` have passed, whichever is sooner.
+fn collect_tail(tail: &Mutex<Vec<u8>>, ended: &Receiver<()>, limit: uint) -> Vec<u8> {
+    let give_up = posix::monotonic_ms() + STDERR_GRACE_MS;
+    while posix::monotonic_ms() < give_up {
+        match ended.try_recv() {
+            Err(TryRecvError::Empty) => sleep(Duration::milliseconds(10)),
+            _ => break
+        }
+    }
+    let tail = tail.lock().unwrap();
+    tail.slice_from(tail.len() - min(tail.len(), limit)).to_vec()
+}
+
+
+/// Waits up to `timeout_ms` for the child to exit, killing it if it
+/// doesn't.  Returns how it exited and whether we killed it.
+fn reap(process: &mut Process, timeout_ms: Option<u64>) -> Result<(ProcessExit, bool), Failure> {
+    process.set_timeout(timeout_ms);
+    match process.wait() {
+        Ok(exit) => return Ok((exit, false)),
+        Err(IoError { kind: TimedOut, .. }) => (),
+        Err(err) => return Err(Failure::Wait(err))
+    }
+    let _ = process.signal_kill();
+    process.set_timeout(None);
+    match process.wait() {
+        Ok(exit) => Ok((exit, true)),
+        Err(err) => Err(Failure::Wait(err))
+    }
+}
+
 
-    let head = cmd_args.get(0);
-    if head.is_none() {
-        print_usage(program_name);
-        set_exit_status(1);
-        return;
+/// The command we're encapsulating.  It is killed if we give up on it
+/// before it has been reaped: dropping a `Process` waits for it with no
+/// timeout, which would hang on exactly the commands `--timeout` is for.
+struct Producer {
+    process: Process,
+}
+
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        // Once the process has been reaped this fails, harmlessly.
+        let _ = self.process.signal_kill();
     }
+}
 
-    let child_executable = head.unwrap();
+
+fn send_command(cmd_args: &[String], opts: &Options, encap_output: &mut Writer) -> Result<([u8; 32], u64), Failure> {
+    let child_executable = &cmd_args[0];
     let mut command = Command::new(child_executable.as_slice());
     for arg in cmd_args.tail().iter() {
         command.arg(arg.as_slice());
     }
     command.stdin(InheritFd(libc::STDIN_FILENO));
-    command.stderr(InheritFd(libc::STDERR_FILENO));
+    if opts.stderr_tail == 0 {
+        command.stderr(InheritFd(libc::STDERR_FILENO));
+    }
 
-    let mut process = match command.spawn() {
-        Ok(p) => p,
-        Err(e) => panic!("failed to execute process: {}", e),
+    let mut producer = match command.spawn() {
+        Ok(process) => Producer { process: process },
+        Err(err) => return Err(Failure::Spawn(err))
     };
 
-    let max_read_len = 32 * 1024;
-    let mut encap_output = stdout();
-    let mut encapper = match ReliableEncap::new(&mut encap_output) {
+    // The child's stderr is only piped to us for `--stderr-tail`.  It is
+    // drained on another thread so the child can't block on it.
+    let tail = Arc::new(Mutex::new(Vec::new()));
+    let (ended_tx, ended_rx) = channel();
+    match producer.process.stderr.take() {
+        Some(mut pipe) => {
+            let limit = opts.stderr_tail;
+            let tail = tail.clone();
+            Thread::spawn(move || {
+                keep_tail(&mut pipe, limit, &*tail);
+                let _ = ended_tx.send(());
+            });
+        },
+        None => drop(ended_tx)
+    }
+
+    let mut encapper = match opts.encap.clone().header(opts.header.clone()).encap(encap_output) {
         Ok(encapper) => encapper,
-        Err(err) => panic!("Error initialising: {}", err)
+        Err(err) => return Err(Failure::Write(err))
+    };
+    let child_stdout = producer.process.stdout.take().unwrap();
+    let fd = child_stdout.as_raw_fd();
+    let mut input = TimeoutReader::new(child_stdout,
+                                       fd,
+                                       opts.idle_timeout_ms,
+                                       opts.timeout_ms);
+    let timed_out = match encode(&mut input, &mut encapper, opts.header.declared_size, opts.heartbeat_ms) {
+        Ok(()) => None,
+        Err(err) => match encode_failure(err) {
+            Failure::TimedOut(why) => Some(why),
+            failure => return Err(failure)
+        }
     };
 
-    let mut buf: Vec<u8> = Vec::with_capacity(max_read_len);
+    // Having stopped reading, there's no point waiting for the child.
+    let wait_ms = match timed_out {
+        Some(_) => Some(0),
+        None => input.remaining_ms()
+    };
+    drop(input);
+    let (exit, timed_out) = match try!(reap(&mut producer.process, wait_ms)) {
+        (exit, true) => (exit, Some(timed_out.unwrap_or("deadline passed"))),
+        (exit, false) => (exit, timed_out)
+    };
 
-    loop {
-        buf.clear();
-        match process.stdout.as_mut().unwrap().push(PIECE_SIZE, &mut buf) {
-            // Don't forget to import the different IoError kinds
-            // if you are going to catch them.  Otherwise you'll get
-            // an E0001 unreachable pattern.
-            Ok(n) => {
-                assert_eq!(buf.len(), n);
-                assert!(encapper.update(&buf).is_ok());
-            },
-            Err(IoError { kind: EndOfFile, .. }) => {
-                assert!(encapper.finish_write().is_ok());
-                break;
-            },
-            Err(err) => panic!("{}", err)
+    // Withholding the final digest is how the reader learns the producer
+    // failed, so only `finalize' once the child has exited cleanly.
+    if exit == ExitStatus(0) && timed_out.is_none() {
+        return match finish(&mut encapper, opts.header.declared_size) {
+            Ok(sent) => Ok(sent),
+            Err(err) => Err(encode_failure(err))
+        };
+    }
+    let ended = if opts.send_status {
+        let status = ProducerStatus {
+            exit: exit,
+            usage: posix::children_usage().unwrap_or(Default::default()),
+            stderr_tail: collect_tail(&*tail, &ended_rx, opts.stderr_tail),
         };
+        encapper.abort(&status)
+    } else {
+        encapper.finish_write()
     };
+    match (ended, timed_out) {
+        (Ok(()), Some(why)) => Err(Failure::TimedOut(why)),
+        (Ok(()), None) => Err(Failure::Producer(exit)),
+        (Err(err), _) => Err(Failure::Write(err))
+    }
+}
 
-    match process.wait() {
-        Ok(ExitStatus(0)) => {
-            assert!(encapper.finalize().is_ok())
-        },
-        Ok(ExitStatus(n)) => {
-            set_exit_status(n);
-        },
-        Ok(ExitSignal(_)) => {
-            set_exit_status(1);
+
+/// Writes the whole stream to `output`, and returns the payload's
+/// SHA-256 and size.
+fn send(opts: &Options, output: &mut Writer) -> Result<([u8; 32], u64), Failure> {
+    match opts.source {
+        // A file's stream is sent the same way as by `reliable-send`.
+        Source::File(ref path) =>
+            match send_file(path.as_slice(), &opts.encap, opts.header.clone(), opts.heartbeat_ms, output) {
+                Ok(sent) => Ok(sent),
+                Err(err) => Err(encode_failure(err))
+            },
+        Source::Command(ref cmd_args) => send_command(cmd_args.as_slice(), opts, output),
+    }
+}
+
+
+/// Like `send`, but with `--pipeline` the stream is written by another
+/// thread while we read and hash the next pieces.
+fn send_to<W: Writer + Send + 'static>(opts: &Options, mut output: W) -> Result<([u8; 32], u64), Failure> {
+    if !opts.pipeline {
+        return send(opts, &mut output);
+    }
+    let mut output = WriteBehind::new(output, pipeline::DEFAULT_DEPTH);
+    let sent = send(opts, &mut output);
+    match (output.finish(), sent) {
+        (Ok(_), sent) => sent,
+        // The failure seen first says more.
+        (Err(_), Err(failure)) => Err(failure),
+        (Err(err), Ok(_)) => Err(Failure::Write(err))
+    }
+}
+
+
+/// `--receipt-key`: the key to check the receipt with, and a fresh nonce
+/// for the stream's header, which the receipt must be signed for.
+fn receipt_key(path: &Path) -> Result<(ReceiptKey, [u8; 16]), Failure> {
+    let key = match ReceiptKey::read_from(path) {
+        Ok(key) => key,
+        Err(err) => return Err(Failure::ReceiptKey(err))
+    };
+    match ReceiptKey::new_nonce() {
+        Ok(nonce) => Ok((key, nonce)),
+        Err(err) => Err(Failure::ReceiptKey(err))
+    }
+}
+
+
+/// `--transport`: sends the stream to `sh -c cmd` and expects a commit
+/// receipt back on its stdout once its stdin is closed.  Succeeds only if
+/// the receipt says the payload we sent was committed, and with `signed`
+/// only if it is signed with that key for the nonce in our header.
+fn send_via(cmd: &str, signed: Option<(ReceiptKey, [u8; 16])>, opts: &Options) -> Result<(), Failure> {
+    let mut transport = match Transport::shell(cmd.as_bytes()) {
+        Ok(transport) => transport,
+        Err(err) => return Err(Failure::Transport(err))
+    };
+    match signed {
+        Some((key, nonce)) => transport.require_signed(key, nonce),
+        None => ()
+    }
+    let sent = send_to(opts, transport.input());
+    match transport.conclude(sent, |&: failure: &Failure| match *failure {
+        Failure::Write(_) => true,
+        _ => false
+    }) {
+        Ok(()) => Ok(()),
+        Err(SendFailure::Send(failure)) => Err(failure),
+        Err(SendFailure::Delivery(delivery)) => Err(Failure::Delivery(delivery)),
+    }
+}
+
+
+fn run(args: &[String]) -> Result<(), Failure> {
+    let mut opts = match parse_args(args) {
+        Some(opts) => opts,
+        None => return Err(Failure::Usage)
+    };
+    let signed = match opts.receipt_key {
+        Some(ref path) => Some(try!(receipt_key(path))),
+        None => None
+    };
+    opts.header.receipt_nonce = signed.as_ref().map(|&(_, nonce)| nonce);
+    match opts.transport {
+        Some(ref cmd) => send_via(cmd.as_slice(), signed, &opts),
+        // `stdout()` is line-buffered, and would split any frame holding
+        // a newline across several writes.
+        None => send_to(&opts, stdout_raw()).map(|_| ())
+    }
+}
+
+
+fn main() {
+    let args = args();
+    let status = match run(args.as_slice()) {
+        Ok(()) => exit_code::SUCCESS,
+        Err(Failure::Usage) => {
+            print_usage(args[0].as_slice());
+            exit_code::USAGE
         },
-        Err(_) => {
-            set_exit_status(1);
+        Err(failure) => {
+            let mut stderr = stderr();
+            let _ = writeln!(&mut stderr, "reliable-encap: {}", failure);
+            failure.exit_code()
         }
-    }
+    };
+    set_exit_status(status);
 }
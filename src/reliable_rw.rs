@@ -7,140 +7,1140 @@
 // except according to those terms.
 #![feature(macro_rules, slicing_syntax)]
 
-use std::io::{IoResult, IoError};
+//! The stream is laid out as follows, with all integers big-endian:
+//!
+//! ```text
+//! stream     := MAGIC_HEADER record* (piece | heartbeat)* status? terminator final
+//! piece      := u32 length (1..piece size)  data  running-digest
+//! terminator := u32 0  running-digest
+//! final      := running-digest
+//! record     := u32 (RECORD_FLAG | kind)  u32 length  body  record-digest
+//! heartbeat  := record of kind RECORD_HEARTBEAT
+//! status     := record of kind RECORD_PRODUCER_STATUS
+//! ```
+//!
+//! The running digest is the SHA-256 of all payload data so far, so the
+//! final one is the plain SHA-256 of the payload, and the stream ends
+//! there: a decoder rejects anything after it.  The encoder leaves out
+//! `final` when its producer fails, and may say why in a `status` record
+//! just before the terminator.  A record's digest is the SHA-256 of
+//! its two header words and body; records are not part of the payload
+//! and don't affect the running digest.  Streams without records are
+//! exactly what the Python implementation produces, and an encoder only
+//! emits records when asked for a feature which needs them.
+//!
+//! `EncapOptions` and `DecapOptions` set up the two ends of the stream.
+//! Their defaults match `ReliableEncap::new` and `ReliableDecap::new`.
 
-use sha256::{Sha256, Digest};
-mod sha256;
+extern crate libc;
+
+use std::cmp::min;
+use std::fmt;
+use std::default::Default;
+use std::iter::repeat;
+use std::io::{IoResult, IoError, EndOfFile, InvalidInput, TimedOut, BufReader, BufWriter, File, FileType};
+use std::os::unix::AsRawFd;
+use std::io::process::{ProcessExit, ExitStatus, ExitSignal};
+use std::rand::{OsRng, Rng};
+use std::slice::bytes::copy_memory;
+use libc::c_int;
+
+use sha256::{Sha256, Digest, hmac_sha256};
+use posix::ResourceUsage;
+use timeout::TimeoutReader;
+
+pub mod encode;
+pub mod exit_code;
+pub mod posix;
+pub mod pipeline;
+pub mod sha256;
+pub mod timeout;
+pub mod transport;
 
 
 /// Magic number at the beginning of the stream
 pub static MAGIC_HEADER: &'static [u8] = b"reliable-encap";
 
-/// We won't emit any pieces longer than this
+/// By default, we won't emit any pieces longer than this
 pub static PIECE_SIZE: uint = 32 * 1024;  // 32kB
 
-/// We won't accept any pieces longer than this
+/// By default, we won't accept any pieces longer than this
 pub static MAX_PIECE_SIZE: uint = 256 * 1024;  // 256kB
 
+/// Set in the length word of a record, which no piece length can be
+pub static RECORD_FLAG: u32 = 0x8000_0000;
+
+/// We won't accept any record bodies longer than this
+pub static MAX_RECORD_SIZE: uint = 64 * 1024;  // 64kB
+
+/// Header record: the total payload length, as a u64
+pub static RECORD_DECLARED_SIZE: u32 = 1;
+
+/// Header record: the source file's mode as a u32, then its mtime in
+/// milliseconds since the epoch as a u64
+pub static RECORD_FILE_METADATA: u32 = 2;
+
+/// Header record: holes in the source file, as (offset, length) pairs of
+/// u64s in ascending order.  Requires a declared size.
+pub static RECORD_HOLES: u32 = 3;
+
+/// Trailer record: how the producer failed.  A u32 which is 0 for an exit
+/// status or 1 for a signal, then that status or signal number as a u32,
+/// its user and system CPU time in microseconds and its peak RSS in KiB
+/// as u64s, and then whatever is left of the body is the tail of its
+/// stderr.
+pub static RECORD_PRODUCER_STATUS: u32 = 4;
+
+/// Sent while the producer is quiet, to show the encoder is still there.
+/// The body is the interval between heartbeats in milliseconds, as a u32.
+pub static RECORD_HEARTBEAT: u32 = 5;
 
+/// Header record: the longest piece the encoder will send, as a u32.  Sent
+/// when the piece size isn't `PIECE_SIZE` or there is a header anyway.
+pub static RECORD_PIECE_SIZE: u32 = 6;
+
+/// Not part of a stream: what the decoding end sends back once it is done
+/// with one.  See `CommitReceipt`.
+pub static RECORD_RECEIPT: u32 = 7;
+
+/// Header record: `RECEIPT_NONCE_SIZE` random bytes from an encoder which
+/// wants a signed receipt.  The signature covers them, so a receipt for
+/// one stream can't be passed off as a receipt for another.
+pub static RECORD_RECEIPT_NONCE: u32 = 8;
+
+/// Not part of a stream: the HMAC-SHA256 which follows a signed receipt.
+/// See `CommitReceipt::sign`.
+pub static RECORD_RECEIPT_MAC: u32 = 9;
+
+/// The length of a receipt nonce
+pub static RECEIPT_NONCE_SIZE: uint = 16;
+
+
+#[derive(Show)]
 pub enum ReliableWriteError {
+    /// A digest in the stream didn't match the data it covers
     IntegrityError,
+    /// The stream isn't something `ReliableEncap` would have produced
     ProtocolError,
+    /// The stream ended part way through a piece or digest
+    TruncatedError,
+    /// The payload's length differs from the length declared in the header
+    LengthError,
+    /// The payload is, or is declared to be, longer than the decoder's
+    /// maximum size
+    SizeLimitError,
+    /// The stream ended cleanly but without its final digest, which is
+    /// how the encoder reports that its producer failed.  Carries the
+    /// producer's status if the encoder sent it.
+    ProducerError(Option<ProducerStatus>),
+    /// Reading the stream timed out, as set up with `timeout::TimeoutReader`
+    TimedOutError(IoError),
     ReadError(IoError),
     WriteError(IoError)
 }
 
 
+impl fmt::String for ReliableWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReliableWriteError::IntegrityError =>
+                write!(f, "integrity error: digest mismatch"),
+            ReliableWriteError::ProtocolError =>
+                write!(f, "protocol error: malformed stream"),
+            ReliableWriteError::TruncatedError =>
+                write!(f, "protocol error: stream truncated"),
+            ReliableWriteError::LengthError =>
+                write!(f, "protocol error: payload length differs from declared size"),
+            ReliableWriteError::SizeLimitError =>
+                write!(f, "payload exceeds size limit"),
+            ReliableWriteError::ProducerError(Some(ref status)) =>
+                write!(f, "producer failed: {}", status),
+            ReliableWriteError::ProducerError(None) =>
+                write!(f, "producer failed: stream ended without final digest"),
+            ReliableWriteError::TimedOutError(ref err) =>
+                write!(f, "timed out reading stream: {}", err.desc),
+            ReliableWriteError::ReadError(ref err) =>
+                write!(f, "read error: {}", err),
+            ReliableWriteError::WriteError(ref err) =>
+                write!(f, "write error: {}", err),
+        }
+    }
+}
+
+
+fn read_error(err: IoError) -> ReliableWriteError {
+    match err.kind {
+        EndOfFile => ReliableWriteError::TruncatedError,
+        TimedOut => ReliableWriteError::TimedOutError(err),
+        _ => ReliableWriteError::ReadError(err)
+    }
+}
+
+
 pub type ReliableWriteResult<T> = Result<T, ReliableWriteError>;
 
 
-pub struct ReliableEncap<'a> {
-    digest: Sha256,
-    output: &'a mut (Writer+'a),
+/// Attributes of the file the payload was read from.
+#[derive(Clone, Copy)]
+pub struct FileMetadata {
+    /// Permission bits, as in `st_mode`
+    pub mode: u32,
+    /// Modification time, in milliseconds since the epoch
+    pub mtime: u64,
 }
 
 
-impl<'a> ReliableEncap<'a> {
-    pub fn new<'b>(output: &'b mut Writer) -> IoResult<ReliableEncap<'b>> {
+/// Optional information the encoder sends ahead of the payload.
+#[derive(Clone, Default)]
+pub struct StreamHeader {
+    /// The exact payload length, if known up front.  The decoder rejects
+    /// a payload of any other length.
+    pub declared_size: Option<u64>,
+    pub metadata: Option<FileMetadata>,
+    /// Ranges of the payload, as (offset, length), which were holes in a
+    /// sparse source file and so read as zeros.  This is only a hint for
+    /// writing the payload back out sparsely: the bytes are still sent.
+    pub holes: Vec<(u64, u64)>,
+    /// The longest piece the encoder will send.  Set from the encoder's
+    /// options rather than by the caller.
+    pub piece_size: Option<u32>,
+    /// For the receiver to sign into its receipt.  See
+    /// `ReceiptKey::new_nonce`.
+    pub receipt_nonce: Option<[u8; 16]>,
+}
+
+
+impl StreamHeader {
+    /// Fills in what can be learned from `file`, if it is a regular file:
+    /// its size (unless already declared), mode, mtime and holes.  Leaves
+    /// `file` positioned at its start.
+    pub fn describe_file(&mut self, file: &mut File) -> IoResult<()> {
+        let stat = try!(file.stat());
+        if stat.kind != FileType::RegularFile {
+            return Ok(());
+        }
+        if self.declared_size.is_none() {
+            self.declared_size = Some(stat.size);
+        }
+        self.metadata = Some(FileMetadata {
+            mode: stat.perm.bits(),
+            mtime: stat.modified,
+        });
+        self.holes = try!(posix::holes(file.as_raw_fd(), stat.size));
+        Ok(())
+    }
+
+    /// Whether there's anything besides the piece size to send.
+    fn is_empty(&self) -> bool {
+        self.declared_size.is_none() && self.metadata.is_none() && self.holes.len() == 0 &&
+            self.receipt_nonce.is_none()
+    }
+}
+
+
+/// When `ReliableEncap` flushes its output.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FlushPolicy {
+    /// Only once the stream ends, and after each heartbeat.  Throughput
+    /// is up to the output's own buffering.
+    AtEnd,
+    /// After every piece as well, so the decoder sees data as soon as
+    /// the encoder has it.
+    EachPiece,
+}
+
+
+/// Builds a `ReliableEncap`, in the manner of `std::io::Command`:
+///
+/// ```ignore
+/// let mut encap = try!(EncapOptions::new().piece_size(64 * 1024).encap(&mut output));
+/// ```
+///
+/// The SHA-256 digests are fixed by the wire format, so there is no
+/// choice of digest.
+#[derive(Clone)]
+pub struct EncapOptions {
+    piece_size: uint,
+    flush: FlushPolicy,
+    header: StreamHeader,
+}
+
+
+impl EncapOptions {
+    pub fn new() -> EncapOptions {
+        EncapOptions {
+            piece_size: PIECE_SIZE,
+            flush: FlushPolicy::AtEnd,
+            header: Default::default(),
+        }
+    }
+
+    /// The longest piece to send.  Pieces longer than `MAX_PIECE_SIZE`
+    /// are only accepted by decoders configured for them.  Panics unless
+    /// `size` fits in a piece's length word.
+    pub fn piece_size(&mut self, size: uint) -> &mut EncapOptions {
+        assert!(0 < size && size < RECORD_FLAG as uint, "piece size out of range");
+        self.piece_size = size;
+        self
+    }
+
+    pub fn flush(&mut self, policy: FlushPolicy) -> &mut EncapOptions {
+        self.flush = policy;
+        self
+    }
+
+    /// Header records to send ahead of the payload.  Decoders which
+    /// predate header records reject any stream which has them, so by
+    /// default there are none.
+    pub fn header(&mut self, header: StreamHeader) -> &mut EncapOptions {
+        self.header = header;
+        self
+    }
+
+    /// Writes the start of the stream to `output`.
+    pub fn encap<'b>(&self, output: &'b mut Writer) -> IoResult<ReliableEncap<'b>> {
+        let mut frame = Vec::with_capacity(FRAME_OVERHEAD + self.piece_size);
+        frame.push_all(&[0u8; 4]);
         let rv = ReliableEncap {
             digest: Sha256::new(),
-            output: output
+            output: output,
+            piece_size: self.piece_size,
+            flush: self.flush,
+            frame: frame,
+            length: 0,
         };
         try!(rv.output.write(MAGIC_HEADER));
+        try!(write_header(rv.output, &self.header, self.piece_size));
         Ok(rv)
     }
+}
 
-    pub fn update(&mut self, buf: &Vec<u8>) -> IoResult<()> {
-        match self.output.write_be_u32(buf.len() as u32) {
-            Ok(()) => (),
-            Err(err) => return Err(err)
+
+fn write_header(output: &mut Writer, header: &StreamHeader, piece_size: uint) -> IoResult<()> {
+    match header.declared_size {
+        Some(size) => {
+            let mut body = Vec::with_capacity(8);
+            try!(body.write_be_u64(size));
+            try!(write_record(output, RECORD_DECLARED_SIZE, body.as_slice()));
+        },
+        None => ()
+    }
+    match header.metadata {
+        Some(metadata) => {
+            let mut body = Vec::with_capacity(12);
+            try!(body.write_be_u32(metadata.mode));
+            try!(body.write_be_u64(metadata.mtime));
+            try!(write_record(output, RECORD_FILE_METADATA, body.as_slice()));
+        },
+        None => ()
+    }
+    if header.holes.len() > 0 && header.declared_size.is_some() {
+        // Holes are only a hint, so any which don't fit are dropped.
+        let mut body = Vec::with_capacity(MAX_RECORD_SIZE);
+        for &(offset, length) in header.holes.iter().take(MAX_RECORD_SIZE / 16) {
+            try!(body.write_be_u64(offset));
+            try!(body.write_be_u64(length));
         }
+        try!(write_record(output, RECORD_HOLES, body.as_slice()));
+    }
+    match header.receipt_nonce {
+        Some(ref nonce) => try!(write_record(output, RECORD_RECEIPT_NONCE, nonce.as_slice())),
+        None => ()
+    }
+    // Don't spoil an otherwise plain stream with a record saying it's plain.
+    if !header.is_empty() || piece_size != PIECE_SIZE {
+        let mut body = Vec::with_capacity(4);
+        try!(body.write_be_u32(piece_size as u32));
+        try!(write_record(output, RECORD_PIECE_SIZE, body.as_slice()));
+    }
+    Ok(())
+}
 
-        self.digest.input(buf.as_slice());
-        match self.output.write(buf.as_slice()) {
-            Ok(()) => (),
-            Err(err) => return Err(err)
+
+/// Builds a `ReliableDecap`.  See `EncapOptions`.
+#[derive(Clone, Copy)]
+pub struct DecapOptions {
+    max_piece_size: uint,
+    max_size: Option<u64>,
+    strict: bool,
+    idle_ms: Option<u64>,
+}
+
+
+impl DecapOptions {
+    pub fn new() -> DecapOptions {
+        DecapOptions {
+            max_piece_size: MAX_PIECE_SIZE,
+            max_size: None,
+            strict: true,
+            idle_ms: None,
         }
+    }
 
-        let hasher_res = self.digest.result_bytes();
+    /// The longest piece to accept.  A stream whose header says it has
+    /// longer pieces is rejected before any payload is read.  Pieces are
+    /// read in chunks, so raising this doesn't raise memory use.
+    pub fn max_piece_size(&mut self, size: uint) -> &mut DecapOptions {
+        self.max_piece_size = size;
+        self
+    }
 
-        match self.output.write(hasher_res.as_slice()) {
-            Ok(()) => (),
-            Err(err) => return Err(err)
+    /// The longest payload to accept.  Decoding stops with
+    /// `SizeLimitError` as soon as a piece would take the payload past
+    /// this, or before any payload if the header declares a larger size.
+    /// Without a limit, a stream can go on for as long as its sender
+    /// likes.
+    pub fn max_size(&mut self, bytes: Option<u64>) -> &mut DecapOptions {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Whether records of unknown kinds are errors, which is the default,
+    /// or are skipped.  Their digests are checked either way.  Skipping
+    /// them lets an old decoder read streams from a newer encoder, at the
+    /// risk of ignoring something which mattered.
+    pub fn strict(&mut self, strict: bool) -> &mut DecapOptions {
+        self.strict = strict;
+        self
+    }
+
+    /// How long the input may go without data, for `timeout_reader`.
+    pub fn idle_timeout(&mut self, ms: Option<u64>) -> &mut DecapOptions {
+        self.idle_ms = ms;
+        self
+    }
+
+    /// Wraps `inner`, which reads from the unbuffered descriptor `fd`,
+    /// to enforce the idle timeout.  `std::io` readers can't time out by
+    /// themselves, so it is up to the caller to decode from the result.
+    pub fn timeout_reader<R: Reader>(&self, inner: R, fd: c_int) -> TimeoutReader<R> {
+        TimeoutReader::new(inner, fd, self.idle_ms, None)
+    }
+
+    /// Reads the start of the stream, up to the end of its header.
+    pub fn decap<'b>(&self, input: &'b mut Reader) -> ReliableWriteResult<ReliableDecap<'b>> {
+        match input.read_exact(MAGIC_HEADER.len()) {
+            Ok(ref magic) if magic.as_slice() == MAGIC_HEADER => (),
+            Ok(_) => return Err(ReliableWriteError::ProtocolError),
+            Err(err) => return Err(read_error(err))
+        }
+
+        let mut rv = ReliableDecap {
+            input: input,
+            options: *self,
+            hasher: Sha256::new(),
+            buffer: repeat(0u8).take(DECODE_CHUNK_SIZE).collect(),
+            header: Default::default(),
+            pending: None,
+            status: None,
+            heartbeat: None,
+            length: 0,
+        };
+        loop {
+            let word = match rv.input.read_be_u32() {
+                Ok(word) => word,
+                Err(err) => return Err(read_error(err))
+            };
+            let kind = word & !RECORD_FLAG;
+            let skip = !rv.options.strict && !is_known_record(kind);
+            // The header ends at the first piece or non-header record.
+            if word & RECORD_FLAG == 0 || !(skip || is_header_record(kind)) {
+                rv.pending = Some(word);
+                try!(rv.check_header());
+                return Ok(rv);
+            }
+            let body = try!(rv.read_record(word));
+            if !skip {
+                try!(rv.apply_header_record(kind, body.as_slice()));
+            }
+        }
+    }
+}
+
+
+/// Why the encoder's producer failed, as sent in its trailer.
+#[derive(Clone, Show)]
+pub struct ProducerStatus {
+    pub exit: ProcessExit,
+    pub usage: ResourceUsage,
+    /// The last bytes the producer wrote to stderr, if the encoder kept
+    /// them.  Not necessarily valid UTF-8.
+    pub stderr_tail: Vec<u8>,
+}
+
+
+/// Bytes of a `RECORD_PRODUCER_STATUS` body ahead of the stderr tail
+static PRODUCER_STATUS_SIZE: uint = 32;
+
+
+impl ProducerStatus {
+    fn to_record(&self) -> Vec<u8> {
+        // Only the end of the tail is worth keeping if it doesn't all fit.
+        let room = MAX_RECORD_SIZE - PRODUCER_STATUS_SIZE;
+        let tail = self.stderr_tail.as_slice();
+        let tail = tail.slice_from(tail.len() - ::std::cmp::min(tail.len(), room));
+
+        let mut body = Vec::with_capacity(PRODUCER_STATUS_SIZE + tail.len());
+        let (how, value) = match self.exit {
+            ExitStatus(n) => (0, n),
+            ExitSignal(n) => (1, n),
+        };
+        let _ = body.write_be_u32(how);
+        let _ = body.write_be_u32(value as u32);
+        let _ = body.write_be_u64(self.usage.user_us);
+        let _ = body.write_be_u64(self.usage.system_us);
+        let _ = body.write_be_u64(self.usage.max_rss_kb);
+        body.push_all(tail);
+        body
+    }
+
+    fn from_record(body: &[u8]) -> Option<ProducerStatus> {
+        if body.len() < PRODUCER_STATUS_SIZE {
+            return None;
+        }
+        let mut reader = BufReader::new(body);
+        let how = reader.read_be_u32().unwrap();
+        let value = reader.read_be_u32().unwrap() as i32 as int;
+        let exit = match how {
+            0 => ExitStatus(value),
+            1 => ExitSignal(value),
+            _ => return None
+        };
+        Some(ProducerStatus {
+            exit: exit,
+            usage: ResourceUsage {
+                user_us: reader.read_be_u64().unwrap(),
+                system_us: reader.read_be_u64().unwrap(),
+                max_rss_kb: reader.read_be_u64().unwrap(),
+            },
+            stderr_tail: body.slice_from(PRODUCER_STATUS_SIZE).to_vec(),
+        })
+    }
+}
+
+
+impl fmt::String for ProducerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.exit {
+            ExitStatus(n) => try!(write!(f, "exited with status {}", n)),
+            ExitSignal(n) => try!(write!(f, "killed by signal {}", n)),
+        }
+        write!(f, " (user {}.{:03}s, system {}.{:03}s, max rss {} KiB)",
+               self.usage.user_us / 1_000_000, self.usage.user_us / 1000 % 1000,
+               self.usage.system_us / 1_000_000, self.usage.system_us / 1000 % 1000,
+               self.usage.max_rss_kb)
+    }
+}
+
+
+/// What became of a stream, as reported back to its sender by
+/// `reliable-write --receipt`.  It travels as a `RECORD_RECEIPT` record: a
+/// u32 status, the payload's u64 size and 32-byte SHA-256, and then the
+/// path it was written to.  The record digest only catches damage in
+/// transit, so anyone who can reach the transport could forge it.  With
+/// `--receipt-key`, the receipt is signed, and a `RECORD_RECEIPT_MAC`
+/// record follows it.
+#[derive(Clone)]
+pub struct CommitReceipt {
+    /// The receiver's exit status; 0 if the payload was committed
+    pub status: u32,
+    /// The payload's size, or 0 if it wasn't received in full
+    pub size: u64,
+    /// The payload's digest, or zeros if it wasn't received in full
+    pub sha256: [u8; 32],
+    pub path: Vec<u8>,
+    /// Set by `sign`
+    pub mac: Option<[u8; 32]>,
+}
+
+
+/// Bytes of a `RECORD_RECEIPT` body ahead of the path
+static RECEIPT_SIZE: uint = 44;
+
+
+impl CommitReceipt {
+    fn body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(RECEIPT_SIZE + self.path.len());
+        body.write_be_u32(self.status).unwrap();
+        body.write_be_u64(self.size).unwrap();
+        body.push_all(self.sha256.as_slice());
+        body.push_all(self.path.as_slice());
+        body
+    }
+
+    /// The HMAC-SHA256 under `key` of the stream's nonce, or zeros if it
+    /// had none, followed by the receipt's record body.
+    fn mac_with(&self, key: &ReceiptKey, nonce: Option<&[u8; 16]>) -> [u8; 32] {
+        let mut message = Vec::with_capacity(RECEIPT_NONCE_SIZE + RECEIPT_SIZE + self.path.len());
+        match nonce {
+            Some(nonce) => message.push_all(nonce.as_slice()),
+            None => message.push_all(&[0u8; 16]),
+        }
+        message.push_all(self.body().as_slice());
+        hmac_sha256(key.key.as_slice(), message.as_slice())
+    }
+
+    /// Signs the receipt for the stream whose header carried `nonce`.  A
+    /// stream without one can't tell its receipt from anyone else's, so
+    /// its sender won't accept a receipt signed without a nonce.
+    pub fn sign(&mut self, key: &ReceiptKey, nonce: Option<&[u8; 16]>) {
+        self.mac = Some(self.mac_with(key, nonce));
+    }
+
+    /// Whether the receipt was signed with `key` for the stream whose
+    /// header carried `nonce`.
+    pub fn verify(&self, key: &ReceiptKey, nonce: &[u8; 16]) -> bool {
+        match self.mac {
+            // Compared in full whatever differs, so the time taken doesn't
+            // say how much of a forgery was right.
+            Some(ref mac) => mac.iter().zip(self.mac_with(key, Some(nonce)).iter())
+                                .fold(0u8, |diff, (&a, &b)| diff | (a ^ b)) == 0,
+            None => false
+        }
+    }
+
+    pub fn write_to(&self, output: &mut Writer) -> IoResult<()> {
+        try!(write_record(output, RECORD_RECEIPT, self.body().as_slice()));
+        match self.mac {
+            Some(ref mac) => try!(write_record(output, RECORD_RECEIPT_MAC, mac.as_slice())),
+            None => ()
+        }
+        output.flush()
+    }
+
+    /// Reads a receipt, and its MAC if it was signed, which must be all
+    /// that `input` holds.
+    pub fn read_from(input: &mut Reader) -> ReliableWriteResult<CommitReceipt> {
+        let word = match input.read_be_u32() {
+            Ok(word) => word,
+            Err(err) => return Err(read_error(err))
+        };
+        if word != RECORD_FLAG | RECORD_RECEIPT {
+            return Err(ReliableWriteError::ProtocolError);
+        }
+        let body = try!(read_record(input, word));
+        if body.len() < RECEIPT_SIZE {
+            return Err(ReliableWriteError::ProtocolError);
+        }
+        let mac = match input.read_byte() {
+            Err(IoError { kind: EndOfFile, .. }) => None,
+            Ok(first) => {
+                let mut word = [first, 0, 0, 0];
+                try!(read_full(input, word.slice_from_mut(1)));
+                let word = BufReader::new(word.as_slice()).read_be_u32().unwrap();
+                if word != RECORD_FLAG | RECORD_RECEIPT_MAC {
+                    return Err(ReliableWriteError::ProtocolError);
+                }
+                let body = try!(read_record(input, word));
+                if body.len() != 32 {
+                    return Err(ReliableWriteError::ProtocolError);
+                }
+                match input.read_byte() {
+                    Err(IoError { kind: EndOfFile, .. }) => (),
+                    Ok(_) => return Err(ReliableWriteError::ProtocolError),
+                    Err(err) => return Err(read_error(err))
+                }
+                let mut mac = [0u8; 32];
+                copy_memory(mac.as_mut_slice(), body.as_slice());
+                Some(mac)
+            },
+            Err(err) => return Err(read_error(err))
+        };
+
+        let mut reader = BufReader::new(body.as_slice());
+        let mut receipt = CommitReceipt {
+            status: reader.read_be_u32().unwrap(),
+            size: reader.read_be_u64().unwrap(),
+            sha256: [0u8; 32],
+            path: body.slice_from(RECEIPT_SIZE).to_vec(),
+            mac: mac,
+        };
+        copy_memory(receipt.sha256.as_mut_slice(), body.slice(12, RECEIPT_SIZE));
+        Ok(receipt)
+    }
+}
+
+
+/// The key `reliable-write --receipt-key` signs receipts with, which the
+/// sender shares.
+pub struct ReceiptKey {
+    key: Vec<u8>,
+}
+
+
+impl ReceiptKey {
+    /// Reads the key from `path`: the whole file, which mustn't be empty.
+    /// It should be at least 32 random bytes.
+    pub fn read_from(path: &Path) -> IoResult<ReceiptKey> {
+        let key = try!(try!(File::open(path)).read_to_end());
+        if key.len() == 0 {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "receipt key is empty",
+                detail: Some(path.display().to_string()),
+            });
+        }
+        Ok(ReceiptKey { key: key })
+    }
+
+    /// A random nonce, for a sender to put in its stream's header and
+    /// then check its receipt against.
+    pub fn new_nonce() -> IoResult<[u8; 16]> {
+        let mut rng = try!(OsRng::new());
+        let mut nonce = [0u8; 16];
+        rng.fill_bytes(nonce.as_mut_slice());
+        Ok(nonce)
+    }
+}
+
+
+fn record_digest(word: u32, body: &[u8]) -> [u8; 32] {
+    let mut words = [0u8; 8];
+    {
+        let mut w = BufWriter::new(words.as_mut_slice());
+        let _ = w.write_be_u32(word);
+        let _ = w.write_be_u32(body.len() as u32);
+    }
+    let mut hasher = Sha256::new();
+    hasher.input(words.as_slice());
+    hasher.input(body);
+    hasher.result_array()
+}
+
+
+fn is_header_record(kind: u32) -> bool {
+    kind == RECORD_DECLARED_SIZE || kind == RECORD_FILE_METADATA || kind == RECORD_HOLES ||
+        kind == RECORD_PIECE_SIZE || kind == RECORD_RECEIPT_NONCE
+}
+
+
+/// Whether this version knows what records of `kind` mean.
+fn is_known_record(kind: u32) -> bool {
+    is_header_record(kind) || kind == RECORD_PRODUCER_STATUS || kind == RECORD_HEARTBEAT
+}
+
+
+/// Reads the rest of a record whose first word was `word`, checks its
+/// digest and returns its body.
+fn read_record(input: &mut Reader, word: u32) -> ReliableWriteResult<Vec<u8>> {
+    let n = match input.read_be_u32() {
+        Ok(n) => n as uint,
+        Err(err) => return Err(read_error(err))
+    };
+    if MAX_RECORD_SIZE < n {
+        return Err(ReliableWriteError::ProtocolError);
+    }
+    let body = match input.read_exact(n) {
+        Ok(body) => body,
+        Err(err) => return Err(read_error(err))
+    };
+    let mut digest = [0u8; 32];
+    try!(read_full(input, digest.as_mut_slice()));
+    if digest.as_slice() != record_digest(word, body.as_slice()).as_slice() {
+        return Err(ReliableWriteError::IntegrityError);
+    }
+    Ok(body)
+}
+
+
+/// Fills `buf` from `input`.
+fn read_full(input: &mut Reader, buf: &mut [u8]) -> ReliableWriteResult<()> {
+    match input.read_at_least(buf.len(), buf) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(read_error(err))
+    }
+}
+
+
+/// Writes a record in one piece, so it isn't split across writes to an
+/// unbuffered output.
+fn write_record(output: &mut Writer, kind: u32, body: &[u8]) -> IoResult<()> {
+    let word = RECORD_FLAG | kind;
+    let mut record = Vec::with_capacity(8 + body.len() + 32);
+    try!(record.write_be_u32(word));
+    try!(record.write_be_u32(body.len() as u32));
+    record.push_all(body);
+    record.push_all(record_digest(word, body).as_slice());
+    output.write(record.as_slice())
+}
+
+
+/// How much of a piece the decoder reads at once.  Pieces are hashed and
+/// written out in chunks of up to this size, so the decoder's memory use
+/// doesn't depend on the piece size.
+static DECODE_CHUNK_SIZE: uint = 32 * 1024;
+
+
+/// Bytes of a piece's frame besides its data: the length word and digest
+static FRAME_OVERHEAD: uint = 4 + 32;
+
+
+pub struct ReliableEncap<'a> {
+    digest: Sha256,
+    output: &'a mut (Writer+'a),
+    piece_size: uint,
+    flush: FlushPolicy,
+    /// The next piece's frame, which is built here and sent with a single
+    /// write.  It always starts with room for the length word, and holds
+    /// the piece's data after that once filled.
+    frame: Vec<u8>,
+    length: u64,
+}
+
+
+impl<'a> ReliableEncap<'a> {
+    pub fn new<'b>(output: &'b mut Writer) -> IoResult<ReliableEncap<'b>> {
+        EncapOptions::new().encap(output)
+    }
+
+    /// Like `new`, but also sends whatever `header` specifies.  Decoders
+    /// which predate header records will reject the stream unless the
+    /// header is empty.
+    pub fn with_header<'b>(output: &'b mut Writer, header: &StreamHeader) -> IoResult<ReliableEncap<'b>> {
+        EncapOptions::new().header(header.clone()).encap(output)
+    }
+
+    /// The longest piece `update` sends.  Reading input in chunks of this
+    /// size makes one piece of each.
+    pub fn piece_size(&self) -> uint {
+        self.piece_size
+    }
+
+    /// The number of payload bytes sent so far.
+    pub fn payload_length(&self) -> u64 {
+        self.length
+    }
+
+    /// The SHA-256 of the payload sent so far.
+    pub fn sha256(&mut self) -> [u8; 32] {
+        self.digest.result_array()
+    }
+
+    /// Sends `buf` as one or more pieces.
+    pub fn update(&mut self, buf: &Vec<u8>) -> IoResult<()> {
+        for piece in buf.as_slice().chunks(self.piece_size) {
+            self.frame.truncate(4);
+            self.frame.push_all(piece);
+            try!(self.send_filled());
         }
         Ok(())
     }
 
-    pub fn finish_write(&mut self) -> IoResult<()> {
-        match self.output.write_be_u32(0) {
-            Ok(()) => (),
-            Err(err) => return Err(err)
+    /// Reads up to a piece's worth of `input` into the next piece, without
+    /// sending it yet, and returns how much was read.  This avoids copying
+    /// the data out of a buffer of the caller's.  Anything read but not
+    /// yet sent is replaced.
+    pub fn fill_from(&mut self, input: &mut Reader) -> IoResult<uint> {
+        self.frame.truncate(4);
+        input.push(self.piece_size, &mut self.frame)
+    }
+
+    /// Sends what `fill_from` read, if anything.
+    pub fn send_filled(&mut self) -> IoResult<()> {
+        let n = self.frame.len() - 4;
+        // An empty piece would be taken for the terminator, so send none.
+        if n == 0 {
+            return Ok(());
         }
-        match self.output.write(self.digest.result_bytes().as_slice()) {
-            Ok(()) => (),
-            Err(err) => return Err(err)
+        {
+            let mut length = BufWriter::new(self.frame.slice_to_mut(4));
+            try!(length.write_be_u32(n as u32));
+        }
+        self.digest.input(self.frame.slice_from(4));
+        self.length += n as u64;
+        self.frame.push_all(self.digest.result_array().as_slice());
+
+        let sent = self.output.write(self.frame.as_slice());
+        self.frame.truncate(4);
+        try!(sent);
+        if self.flush == FlushPolicy::EachPiece {
+            try!(self.output.flush());
         }
         Ok(())
     }
 
+    pub fn finish_write(&mut self) -> IoResult<()> {
+        // A zero length word and the digest
+        let mut terminator = [0u8; 4 + 32];
+        copy_memory(terminator.slice_from_mut(4), self.digest.result_array().as_slice());
+        self.output.write(terminator.as_slice())
+    }
+
     pub fn finalize(&mut self) -> IoResult<()> {
         // self.digest.input(MAGIC_HEADER);
-        try!(self.output.write(self.digest.result_bytes().as_slice()));
+        try!(self.output.write(self.digest.result_array().as_slice()));
         self.output.flush()
     }
+
+    /// Tells the decoder we're still here, although there's no data to
+    /// send.  `interval_ms` is how often the caller sends these.  Flushes,
+    /// since a heartbeat stuck in a buffer is no use.
+    pub fn heartbeat(&mut self, interval_ms: u32) -> IoResult<()> {
+        let mut body = Vec::with_capacity(4);
+        try!(body.write_be_u32(interval_ms));
+        try!(write_record(self.output, RECORD_HEARTBEAT, body.as_slice()));
+        self.output.flush()
+    }
+
+    /// Ends the stream without a final digest, after saying why.  Use
+    /// instead of `finish_write` and `finalize`.  Decoders which predate
+    /// status records see a malformed stream rather than a failed
+    /// producer, which still stops them committing it.
+    pub fn abort(&mut self, status: &ProducerStatus) -> IoResult<()> {
+        try!(write_record(self.output, RECORD_PRODUCER_STATUS, status.to_record().as_slice()));
+        try!(self.finish_write());
+        self.output.flush()
+    }
+}
+
+
+/// The SHA-256 of everything `input` yields until end of file.
+pub fn sha256_of(input: &mut Reader) -> IoResult<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 32 * 1024];
+    loop {
+        match input.read(buf.as_mut_slice()) {
+            Ok(n) => hasher.input(buf.slice_to(n)),
+            Err(IoError { kind: EndOfFile, .. }) => break,
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(hasher.result_array())
 }
 
 
-pub fn copy_out(input: &mut Reader, output: &mut Writer) -> ReliableWriteResult<()> {
-    let mut hasher: Box<Digest> = box Sha256::new();
+/// Decodes a stream in two steps: `new` reads up to the end of the
+/// header, so the caller can act on it before any payload arrives, and
+/// `copy_to` then reads the rest.
+pub struct ReliableDecap<'a> {
+    input: &'a mut (Reader+'a),
+    options: DecapOptions,
+    hasher: Sha256,
+    /// Where pieces are read, a chunk at a time, to be hashed and passed on
+    buffer: Vec<u8>,
+    header: StreamHeader,
+    /// The first word after the header, read while looking for its end
+    pending: Option<u32>,
+    /// From a status record; only a terminator may follow one
+    status: Option<ProducerStatus>,
+    /// From the latest heartbeat
+    heartbeat: Option<u32>,
+    length: u64,
+}
+
 
-    match input.read_exact(MAGIC_HEADER.len()) {
-        Ok(_) => (),
-        Err(err) => return Err(ReliableWriteError::ReadError(err))
+impl<'a> ReliableDecap<'a> {
+    pub fn new<'b>(input: &'b mut Reader) -> ReliableWriteResult<ReliableDecap<'b>> {
+        DecapOptions::new().decap(input)
     }
 
-    loop {
-        let n = match input.read_be_u32() {
-            Ok(n) => {
-                let n = n as uint;
-                if MAX_PIECE_SIZE < n {
-                    return Err(ReliableWriteError::ProtocolError);
-                }
-                n
-            },
-            Err(err) => return Err(ReliableWriteError::ReadError(err))
-        };
-        let data = match input.read_exact(n) {
-            Ok(data) => data,
-            Err(err) => return Err(ReliableWriteError::ReadError(err))
+    /// What the encoder told us ahead of the payload.
+    pub fn header(&self) -> &StreamHeader {
+        &self.header
+    }
+
+    /// The interval the encoder said it sends heartbeats at, in
+    /// milliseconds, once one has arrived.  A stream which stalls for
+    /// much longer than that has lost its encoder, rather than waiting on
+    /// a slow producer.
+    pub fn heartbeat_interval(&self) -> Option<u32> {
+        self.heartbeat
+    }
+
+    /// The number of payload bytes decoded so far.
+    pub fn payload_length(&self) -> u64 {
+        self.length
+    }
+
+    fn read_record(&mut self, word: u32) -> ReliableWriteResult<Vec<u8>> {
+        read_record(self.input, word)
+    }
+
+    fn apply_header_record(&mut self, kind: u32, body: &[u8]) -> ReliableWriteResult<()> {
+        let mut reader = BufReader::new(body);
+        if kind == RECORD_DECLARED_SIZE && body.len() == 8 && self.header.declared_size.is_none() {
+            self.header.declared_size = Some(reader.read_be_u64().unwrap());
+        } else if kind == RECORD_FILE_METADATA && body.len() == 12 && self.header.metadata.is_none() {
+            self.header.metadata = Some(FileMetadata {
+                mode: reader.read_be_u32().unwrap(),
+                mtime: reader.read_be_u64().unwrap(),
+            });
+        } else if kind == RECORD_HOLES && body.len() % 16 == 0 && self.header.holes.len() == 0 {
+            for _ in range(0, body.len() / 16) {
+                let offset = reader.read_be_u64().unwrap();
+                let length = reader.read_be_u64().unwrap();
+                self.header.holes.push((offset, length));
+            }
+        } else if kind == RECORD_PIECE_SIZE && body.len() == 4 && self.header.piece_size.is_none() {
+            self.header.piece_size = Some(reader.read_be_u32().unwrap());
+        } else if kind == RECORD_RECEIPT_NONCE && body.len() == RECEIPT_NONCE_SIZE &&
+                  self.header.receipt_nonce.is_none() {
+            let mut nonce = [0u8; 16];
+            copy_memory(nonce.as_mut_slice(), body);
+            self.header.receipt_nonce = Some(nonce);
+        } else {
+            return Err(ReliableWriteError::ProtocolError);
+        }
+        Ok(())
+    }
+
+    /// Checks the header as a whole once all of it has arrived.
+    fn check_header(&self) -> ReliableWriteResult<()> {
+        match self.header.piece_size {
+            Some(size) if size == 0 || self.options.max_piece_size < size as uint =>
+                return Err(ReliableWriteError::ProtocolError),
+            _ => ()
+        }
+        match (self.header.declared_size, self.options.max_size) {
+            (Some(size), Some(max)) if max < size => return Err(ReliableWriteError::SizeLimitError),
+            _ => ()
+        }
+        if self.header.holes.len() == 0 {
+            return Ok(());
+        }
+        let size = match self.header.declared_size {
+            Some(size) => size,
+            None => return Err(ReliableWriteError::ProtocolError)
         };
+        // Holes must be non-empty, in order, disjoint and within the payload.
+        let mut end = 0;
+        for &(offset, length) in self.header.holes.iter() {
+            if length == 0 || offset < end || size < offset || size - offset < length {
+                return Err(ReliableWriteError::ProtocolError);
+            }
+            end = offset + length;
+        }
+        Ok(())
+    }
 
-        hasher.input(data.as_slice());
+    /// Handles a record found among the pieces: a heartbeat, or a
+    /// producer status, after which only the terminator may follow.
+    fn read_trailer_record(&mut self, word: u32) -> ReliableWriteResult<()> {
+        let body = try!(self.read_record(word));
+        let kind = word & !RECORD_FLAG;
+        if !self.options.strict && !is_known_record(kind) {
+            return Ok(());
+        }
+        if self.status.is_some() {
+            return Err(ReliableWriteError::ProtocolError);
+        }
+        if kind == RECORD_HEARTBEAT && body.len() == 4 {
+            self.heartbeat = Some(BufReader::new(body.as_slice()).read_be_u32().unwrap());
+            return Ok(());
+        }
+        if kind != RECORD_PRODUCER_STATUS {
+            return Err(ReliableWriteError::ProtocolError);
+        }
+        match ProducerStatus::from_record(body.as_slice()) {
+            Some(status) => {
+                self.status = Some(status);
+                Ok(())
+            },
+            None => Err(ReliableWriteError::ProtocolError)
+        }
+    }
 
-        match output.write(data.as_slice()) {
-            Ok(_) => (),
-            Err(err) => return Err(ReliableWriteError::WriteError(err))
+    /// Decodes the payload into `output`.  On success, returns the
+    /// SHA-256 of the payload.  The stream must end with its final digest:
+    /// this reads to the end of the input.
+    pub fn copy_to(&mut self, output: &mut Writer) -> ReliableWriteResult<[u8; 32]> {
+        let max_piece_size = match self.header.piece_size {
+            Some(size) => size as uint,
+            None => self.options.max_piece_size
         };
+        loop {
+            let word = match self.pending.take() {
+                Some(word) => word,
+                None => match self.input.read_be_u32() {
+                    Ok(word) => word,
+                    Err(err) => return Err(read_error(err))
+                }
+            };
+            if word & RECORD_FLAG != 0 {
+                try!(self.read_trailer_record(word));
+                continue;
+            }
+            let n = word as uint;
+            if self.status.is_some() && n != 0 {
+                return Err(ReliableWriteError::ProtocolError);
+            }
+            if max_piece_size < n {
+                return Err(ReliableWriteError::ProtocolError);
+            }
+            match self.options.max_size {
+                Some(max) if max < self.length + n as u64 =>
+                    return Err(ReliableWriteError::SizeLimitError),
+                _ => ()
+            }
+            // Whether a short payload was a failed producer is only known
+            // once the final digest does or doesn't follow.
+            match self.header.declared_size {
+                Some(size) if size < self.length + n as u64 =>
+                    return Err(ReliableWriteError::LengthError),
+                _ => ()
+            }
+
+            // The data is passed on before the piece's digest has been
+            // checked, as it always was: only the final digest makes the
+            // payload good, so the caller mustn't act on it before that.
+            let mut left = n;
+            while left > 0 {
+                let chunk = min(left, self.buffer.len());
+                try!(read_full(self.input, self.buffer.slice_to_mut(chunk)));
+                self.hasher.input(self.buffer.slice_to(chunk));
+                match output.write(self.buffer.slice_to(chunk)) {
+                    Ok(_) => (),
+                    Err(err) => return Err(ReliableWriteError::WriteError(err))
+                };
+                left -= chunk;
+            }
+            self.length += n as u64;
 
-        let hash_data = match input.read_exact(hasher.output_bits() / 8) {
-            Ok(data) => data,
-            Err(err) => return Err(ReliableWriteError::ReadError(err))
+            let mut hash_data = [0u8; 32];
+            try!(read_full(self.input, hash_data.as_mut_slice()));
+            if hash_data.as_slice() != self.hasher.result_array().as_slice() {
+                return Err(ReliableWriteError::IntegrityError);
+            }
+
+            if n == 0 {
+                break;
+            }
+        }
+        // The encoder withholds the final digest when its producer fails, so
+        // running out of stream right here is a report rather than damage.
+        let mut hash_data = [0u8; 32];
+        hash_data[0] = match self.input.read_byte() {
+            Ok(_) if self.status.is_some() => return Err(ReliableWriteError::ProtocolError),
+            Ok(byte) => byte,
+            Err(IoError { kind: EndOfFile, .. }) =>
+                return Err(ReliableWriteError::ProducerError(self.status.take())),
+            Err(err) => return Err(read_error(err))
         };
-        if hash_data != hasher.result_bytes() {
+        match self.header.declared_size {
+            Some(size) if size != self.length => return Err(ReliableWriteError::LengthError),
+            _ => ()
+        }
+        try!(read_full(self.input, hash_data.slice_from_mut(1)));
+        let digest = self.hasher.result_array();
+        if hash_data.as_slice() != digest.as_slice() {
             return Err(ReliableWriteError::IntegrityError);
         }
-
-        if n == 0 {
-            break;
+        // Nothing follows the final digest.  If something does, this is
+        // not the stream that was sent, however well its start checks out.
+        match self.input.read_byte() {
+            Ok(_) => return Err(ReliableWriteError::ProtocolError),
+            Err(IoError { kind: EndOfFile, .. }) => (),
+            Err(err) => return Err(read_error(err))
         }
+
+        Ok(digest)
     }
-    let hash_data = match input.read_exact(hasher.output_bits() / 8) {
-        Ok(data) => data,
-        Err(err) => return Err(ReliableWriteError::ReadError(err))
-    };
-    if hash_data != hasher.result_bytes() {
-        return Err(ReliableWriteError::IntegrityError);
-    }
+}
 
-    Ok(())
+
+/// Decodes a stream from `input`, writing the payload to `output`.  On
+/// success, returns the SHA-256 of the payload.
+pub fn copy_out(input: &mut Reader, output: &mut Writer) -> ReliableWriteResult<[u8; 32]> {
+    let mut decap = try!(ReliableDecap::new(input));
+    decap.copy_to(output)
 }
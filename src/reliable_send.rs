@@ -0,0 +1,254 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate reliable_rw;
+
+use std::fmt;
+use std::default::Default;
+use std::os::{args, set_exit_status};
+use std::io::{stderr, IoError};
+use reliable_rw::{exit_code, EncapOptions, ReceiptKey, StreamHeader};
+use reliable_rw::encode::{send_file, EncodeError};
+use reliable_rw::transport::{shell_quote, split_dest, Transport, Delivery, SendFailure};
+
+
+/// Why a run didn't end with the payload committed.
+enum Failure {
+    Usage,
+    Open(IoError),
+    Read(IoError),
+    Write(IoError),
+    /// SRC changed size while it was read: (size when opened, bytes read)
+    SizeChanged(u64, u64),
+    /// The transport couldn't be started
+    Transport(IoError),
+    /// `--receipt-key` couldn't be read, or no nonce made for it
+    ReceiptKey(IoError),
+    /// The receiver didn't confirm it committed what we sent
+    Delivery(Delivery),
+}
+
+
+impl Failure {
+    fn exit_code(&self) -> int {
+        match *self {
+            Failure::Usage => exit_code::USAGE,
+            Failure::Open(_) => exit_code::READ_IO,
+            Failure::Read(_) => exit_code::READ_IO,
+            Failure::Write(_) => exit_code::WRITE_IO,
+            Failure::SizeChanged(..) => exit_code::UNEXPECTED_CONTENT,
+            Failure::Transport(_) => exit_code::WRITE_IO,
+            Failure::ReceiptKey(_) => exit_code::READ_IO,
+            Failure::Delivery(ref delivery) => delivery.exit_code(),
+        }
+    }
+}
+
+
+impl fmt::String for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Usage => write!(f, "usage error"),
+            Failure::Open(ref err) => write!(f, "could not open input: {}", err),
+            Failure::Read(ref err) => write!(f, "error reading input: {}", err),
+            Failure::Write(ref err) => write!(f, "error writing stream: {}", err),
+            Failure::SizeChanged(declared, sent) =>
+                write!(f, "input changed size from {} to {} bytes while being sent", declared, sent),
+            Failure::Transport(ref err) => write!(f, "could not run transport: {}", err),
+            Failure::ReceiptKey(ref err) => write!(f, "could not set up receipt key: {}", err),
+            Failure::Delivery(ref delivery) => write!(f, "{}", delivery),
+        }
+    }
+}
+
+
+fn print_usage(program: &str) {
+    let mut stderr = stderr();
+    let _ = writeln!(&mut stderr, "{} [options] SRC [user@]host:DEST", program);
+    let _ = writeln!(&mut stderr, "{} [options] SRC DEST", program);
+    let _ = writeln!(&mut stderr, "");
+    let _ = writeln!(&mut stderr, "Sends SRC (`-` for stdin) to `reliable-write --receipt DEST` on host, and");
+    let _ = writeln!(&mut stderr, "exits 0 only once DEST holds exactly what was sent.  Without a host the");
+    let _ = writeln!(&mut stderr, "receiver is run locally.");
+    let _ = writeln!(&mut stderr, "");
+    let _ = writeln!(&mut stderr, "options:");
+    let _ = writeln!(&mut stderr, "  --transport CMD        reach the host with `CMD host command` (default ssh)");
+    let _ = writeln!(&mut stderr, "  --remote-command CMD   run CMD as the receiver (default reliable-write)");
+    let _ = writeln!(&mut stderr, "  --receipt-key FILE     only accept a receipt signed with the key in FILE;");
+    let _ = writeln!(&mut stderr, "                         give the receiver its copy with --remote-command");
+}
+
+
+struct Options {
+    /// `--transport`: prefixed to the host and the receiver's command line
+    transport: String,
+    /// `--remote-command`: the receiver, and any options to give it
+    remote_command: String,
+    /// `--receipt-key`: the file holding the key the receipt must be
+    /// signed with
+    receipt_key: Option<Path>,
+    source: String,
+    /// Where DEST is, if not here
+    host: Option<String>,
+    dest: String,
+}
+
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut cmd_args: &[String] = args.tail();
+    let mut transport = "ssh".to_string();
+    let mut remote_command = "reliable-write".to_string();
+    let mut receipt_key = None;
+
+    loop {
+        let head = cmd_args.get(0).map(|arg| arg.as_slice());
+        if head == Some("--transport") || head == Some("--remote-command") {
+            let cmd = match cmd_args.get(1) {
+                Some(cmd) => cmd.clone(),
+                None => return None
+            };
+            if head == Some("--transport") {
+                transport = cmd;
+            } else {
+                remote_command = cmd;
+            }
+            cmd_args = cmd_args.slice_from(2);
+        } else if head == Some("--receipt-key") {
+            receipt_key = match cmd_args.get(1) {
+                Some(path) => Some(Path::new(path.as_slice())),
+                None => return None
+            };
+            cmd_args = cmd_args.slice_from(2);
+        } else if head == Some("--") {
+            cmd_args = cmd_args.tail();
+            break;
+        } else {
+            break;
+        }
+    }
+
+    if cmd_args.len() != 2 {
+        return None;
+    }
+    let (host, dest) = match split_dest(cmd_args[1].as_slice()) {
+        Some(split) => split,
+        None => return None
+    };
+    Some(Options {
+        transport: transport,
+        remote_command: remote_command,
+        receipt_key: receipt_key,
+        source: cmd_args[0].clone(),
+        host: host,
+        dest: dest,
+    })
+}
+
+
+/// The `sh -c` command line which runs the receiver, through the
+/// transport if DEST is on another host.
+fn command_line(opts: &Options) -> Vec<u8> {
+    let mut receiver: Vec<u8> = Vec::new();
+    receiver.push_all(opts.remote_command.as_bytes());
+    receiver.push_all(b" --receipt ");
+    receiver.push_all(shell_quote(opts.dest.as_bytes()).as_slice());
+
+    match opts.host {
+        // Like ssh, the transport is expected to hand its arguments to a
+        // shell on the far side, so the receiver's line is quoted once
+        // for that shell and once for ours.
+        Some(ref host) => {
+            let mut line: Vec<u8> = Vec::new();
+            line.push_all(opts.transport.as_bytes());
+            line.push(b' ');
+            line.push_all(shell_quote(host.as_bytes()).as_slice());
+            line.push(b' ');
+            line.push_all(shell_quote(receiver.as_slice()).as_slice());
+            line
+        },
+        None => receiver
+    }
+}
+
+
+/// `--receipt-key`: the key to check the receipt with, and a fresh nonce
+/// for the stream's header, which the receipt must be signed for.
+fn receipt_key(path: &Path) -> Result<(ReceiptKey, [u8; 16]), Failure> {
+    let key = match ReceiptKey::read_from(path) {
+        Ok(key) => key,
+        Err(err) => return Err(Failure::ReceiptKey(err))
+    };
+    match ReceiptKey::new_nonce() {
+        Ok(nonce) => Ok((key, nonce)),
+        Err(err) => Err(Failure::ReceiptKey(err))
+    }
+}
+
+
+/// Writes the stream for SRC to `output`, with `nonce` in its header if
+/// the receipt is to be signed, and returns the payload's SHA-256 and size.
+fn send(opts: &Options, nonce: Option<[u8; 16]>, output: &mut Writer) -> Result<([u8; 32], u64), Failure> {
+    let header = StreamHeader { receipt_nonce: nonce, ..Default::default() };
+    match send_file(opts.source.as_slice(), &EncapOptions::new(), header, None, output) {
+        Ok(sent) => Ok(sent),
+        Err(EncodeError::Open(err)) => Err(Failure::Open(err)),
+        Err(EncodeError::Read(err)) => Err(Failure::Read(err)),
+        Err(EncodeError::Write(err)) => Err(Failure::Write(err)),
+        Err(EncodeError::SizeChanged(declared, sent)) => Err(Failure::SizeChanged(declared, sent)),
+    }
+}
+
+
+fn run(args: &[String]) -> Result<(), Failure> {
+    let opts = match parse_args(args) {
+        Some(opts) => opts,
+        None => return Err(Failure::Usage)
+    };
+    let signed = match opts.receipt_key {
+        Some(ref path) => Some(try!(receipt_key(path))),
+        None => None
+    };
+    let mut transport = match Transport::shell(command_line(&opts).as_slice()) {
+        Ok(transport) => transport,
+        Err(err) => return Err(Failure::Transport(err))
+    };
+    let nonce = match signed {
+        Some((key, nonce)) => {
+            transport.require_signed(key, nonce);
+            Some(nonce)
+        },
+        None => None
+    };
+    let sent = send(&opts, nonce, &mut transport.input());
+    match transport.conclude(sent, |&: failure: &Failure| match *failure {
+        Failure::Write(_) => true,
+        _ => false
+    }) {
+        Ok(()) => Ok(()),
+        Err(SendFailure::Send(failure)) => Err(failure),
+        Err(SendFailure::Delivery(delivery)) => Err(Failure::Delivery(delivery)),
+    }
+}
+
+
+fn main() {
+    let args = args();
+    let status = match run(args.as_slice()) {
+        Ok(()) => exit_code::SUCCESS,
+        Err(Failure::Usage) => {
+            print_usage(args[0].as_slice());
+            exit_code::USAGE
+        },
+        Err(failure) => {
+            let mut stderr = stderr();
+            let _ = writeln!(&mut stderr, "reliable-send: {}", failure);
+            failure.exit_code()
+        }
+    };
+    set_exit_status(status);
+}
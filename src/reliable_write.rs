@@ -7,65 +7,1518 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+extern crate libc;
 extern crate reliable_rw;
 
+use std::fmt;
 use std::os;
-use std::io::{stdin, stderr, File, Open, Write, Writer};
-use std::io::fs::{unlink, rename};
+use std::ffi::CString;
+use std::io::{stdout, stderr, File, Append, Write, Writer, Command, IoResult, IoError};
+use std::io::BufferedReader;
+use std::io::stdio::stdin_raw;
+use std::io::{FileType, FileStat, FilePermission, USER_RWX, FileNotFound, PathAlreadyExists, SeekCur};
+use std::io::MismatchedFileTypeForOperation;
+use std::io::fs::{unlink, rename, readdir, lstat, mkdir, rmdir_recursive, chmod, change_file_times};
+use std::io::fs::PathExtensions;
+use std::cmp::min;
+use std::num::Int;
+use std::io::process::{InheritFd, Ignored, ProcessExit, ExitStatus};
+use std::io::timer::sleep;
+use std::os::unix::AsRawFd;
+use std::rand::random;
+use std::time::Duration;
+use libc::c_char;
 
 use reliable_rw::{
-    copy_out,
+    sha256_of,
+    CommitReceipt,
+    DecapOptions,
+    FileMetadata,
+    ReceiptKey,
+    ReliableDecap,
     ReliableWriteError,
+    ReliableWriteResult,
 };
+use reliable_rw::{exit_code, pipeline, posix};
+use reliable_rw::pipeline::{ReadAhead, WriteBehind};
+use reliable_rw::posix::{FileLock, Preallocation};
+use reliable_rw::sha256::{to_hex, digest_from_hex};
+
+
+/// Temp files are named `<target><TEMP_MARKER><pid>`, with `.<random>`
+/// added if something we can't remove already has that name.  The marker
+/// is how `--sweep-stale` recognises files it is allowed to remove.
+static TEMP_MARKER: &'static [u8] = b".reliable-tmp.";
+
+/// How many names to try for a temp file before giving up.
+static TEMP_ATTEMPTS: uint = 8;
+
+/// With `--tree --keep-old`, the replaced tree is kept at `<target><OLD_SUFFIX>`.
+static OLD_SUFFIX: &'static [u8] = b".old";
+
+/// `--lock` locks `<target><LOCK_SUFFIX>`.  Lock files are never removed:
+/// unlinking one another writer has open would let two writers in at once.
+static LOCK_SUFFIX: &'static [u8] = b".lock";
+
+/// How often `--lock-wait` retries a held lock.
+static LOCK_POLL_MS: u64 = 100;
+
+
+/// The temp file's path for `cleanup_on_signal`, or null when there is
+/// nothing to clean up.
+static mut CLEANUP_PATH: *const c_char = 0 as *const c_char;
+
+
+extern "C" fn cleanup_on_signal(signum: libc::c_int) {
+    unsafe {
+        if !CLEANUP_PATH.is_null() {
+            posix::unlink_raw(CLEANUP_PATH);
+        }
+    }
+    posix::reraise(signum);
+}
+
+
+fn with_suffix(target: &Path, suffix: &[u8]) -> Path {
+    let mut name = target.clone().into_vec();
+    name.push_all(suffix);
+    Path::new(name)
+}
+
+
+fn temp_path(target: &Path, random: Option<u32>) -> Path {
+    let mut suffix = TEMP_MARKER.to_vec();
+    suffix.push_all(posix::getpid().to_string().as_bytes());
+    match random {
+        Some(random) => suffix.push_all(format!(".{:08x}", random).as_bytes()),
+        None => ()
+    }
+    with_suffix(target, suffix.as_slice())
+}
+
+
+/// Removes `path` if it is a file or directory owned by us, without
+/// following it if it is a symlink.  Returns whether `path` is now free.
+fn remove_if_ours(path: &Path) -> IoResult<bool> {
+    let stat = match lstat(path) {
+        Ok(stat) => stat,
+        Err(IoError { kind: FileNotFound, .. }) => return Ok(true),
+        Err(err) => return Err(err)
+    };
+    if stat.unstable.uid as libc::uid_t != posix::getuid() {
+        return Ok(false);
+    }
+    match stat.kind {
+        FileType::RegularFile => try!(unlink(path)),
+        FileType::Directory => try!(rmdir_recursive(path)),
+        _ => return Ok(false)
+    }
+    Ok(true)
+}
+
+
+/// Removes `path`, and everything under it if it is a directory.  A
+/// symlink is removed, not followed.
+fn remove_tree(path: &Path) -> IoResult<()> {
+    if try!(lstat(path)).kind == FileType::Directory {
+        rmdir_recursive(path)
+    } else {
+        unlink(path)
+    }
+}
+
+
+/// Makes a temp file or directory for `target` with `create`, which must
+/// fail with `PathAlreadyExists` rather than reuse or follow anything
+/// already at the path it is given.  Something at our usual name was left
+/// by an earlier run which had our pid and was killed, and is removed if
+/// it is ours; otherwise, or if it comes back, a random name is used.
+fn create_unique<T, F>(target: &Path, mut create: F) -> IoResult<(Path, T)>
+    where F: FnMut(&Path) -> IoResult<T>
+{
+    let mut path = temp_path(target, None);
+    let mut attempts = 1;
+    loop {
+        match create(&path) {
+            Ok(made) => return Ok((path, made)),
+            Err(IoError { kind: PathAlreadyExists, .. }) if attempts < TEMP_ATTEMPTS => {
+                if attempts > 1 || !try!(remove_if_ours(&path)) {
+                    path = temp_path(target, Some(random()));
+                }
+                attempts += 1;
+            },
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+
+/// The temporary file (or, with `--tree`, directory) a transfer is written
+/// into.  Unless `commit` moves it into place, it is removed when the guard
+/// is dropped -- on error returns and panics alike.  A temp file is also
+/// unlinked if we are killed by SIGHUP, SIGINT or SIGTERM; a directory
+/// can't be removed from a signal handler and is left for `--sweep-stale`.
+/// SIGPIPE stays ignored, so a closed stdout or stderr is an ordinary
+/// write error and cleaned up after like any other.
+struct TempFile {
+    path: Path,
+    c_path: CString,
+    is_dir: bool,
+    committed: bool,
+}
+
+
+impl TempFile {
+    fn create(target: &Path) -> IoResult<(TempFile, File)> {
+        let (path, file) = try!(create_unique(target, |&mut: path: &Path| posix::create_new(path)));
+        let guard = TempFile {
+            c_path: CString::from_slice(path.as_vec()),
+            path: path,
+            is_dir: false,
+            committed: false,
+        };
+        unsafe { CLEANUP_PATH = guard.c_path.as_ptr(); }
+        for &signum in [posix::SIGHUP, posix::SIGINT, posix::SIGTERM].iter() {
+            posix::set_signal_handler(signum, cleanup_on_signal);
+        }
+        Ok((guard, file))
+    }
+
+    fn create_dir(target: &Path) -> IoResult<TempFile> {
+        let (path, ()) = try!(create_unique(target, |&mut: path: &Path| mkdir(path, USER_RWX)));
+        Ok(TempFile {
+            c_path: CString::from_slice(path.as_vec()),
+            path: path,
+            is_dir: true,
+            committed: false,
+        })
+    }
+
+    /// Renames the temp file over `target`, or with `noreplace` only if
+    /// `target` doesn't exist.
+    fn commit(mut self, target: &Path, noreplace: bool) -> IoResult<()> {
+        if noreplace {
+            try!(posix::rename_noreplace(&self.path, target));
+        } else {
+            try!(rename(&self.path, target));
+        }
+        self.committed = true;
+        unsafe { CLEANUP_PATH = 0 as *const c_char; }
+        Ok(())
+    }
+
+    /// Swaps the staged tree into place as one atomic step, so readers of
+    /// `target` see either the complete old tree or the complete new one.
+    /// The old tree then sits at our temp path.  With `keep_old` it is
+    /// moved to `<target>.old`, swapping out any previous `.old`, which is
+    /// deleted only once the new one is in place; otherwise it is deleted.
+    /// Once the swap is done the new tree is committed, so failing to tidy
+    /// up is only warned about.  With `noreplace` there is never a swap:
+    /// the tree is only moved into place if `target` doesn't exist.  A
+    /// `target` which exists but isn't a directory, such as a file or a
+    /// symlink, is never swapped out.
+    fn commit_tree(mut self, target: &Path, keep_old: bool, noreplace: bool) -> IoResult<()> {
+        let exists = noreplace || match lstat(target) {
+            Ok(ref stat) if stat.kind == FileType::Directory => true,
+            Ok(_) => return Err(IoError {
+                kind: MismatchedFileTypeForOperation,
+                desc: "target exists and is not a directory",
+                detail: Some(target.display().to_string()),
+            }),
+            Err(IoError { kind: FileNotFound, .. }) => false,
+            Err(err) => return Err(err)
+        };
+        if noreplace || !exists {
+            try!(posix::rename_noreplace(&self.path, target));
+            self.committed = true;
+            return Ok(());
+        }
+
+        let old = with_suffix(target, OLD_SUFFIX);
+        let old_exists = if keep_old {
+            match lstat(&old) {
+                Ok(ref stat) if stat.kind == FileType::Directory => true,
+                Ok(_) => return Err(IoError {
+                    kind: PathAlreadyExists,
+                    desc: "not a directory where the old tree is kept",
+                    detail: Some(old.display().to_string()),
+                }),
+                Err(IoError { kind: FileNotFound, .. }) => false,
+                Err(err) => return Err(err)
+            }
+        } else {
+            false
+        };
+        try!(posix::rename_exchange(&self.path, target));
+        self.committed = true;
+
+        let tidied = if !keep_old {
+            remove_tree(&self.path)
+        } else if old_exists {
+            posix::rename_exchange(&self.path, &old).and_then(|()| remove_tree(&self.path))
+        } else {
+            posix::rename_noreplace(&self.path, &old)
+        };
+        match tidied {
+            Ok(()) => (),
+            Err(err) => {
+                let mut stderr = stderr();
+                let _ = writeln!(&mut stderr, "reliable-write: warning: committed, but an old tree \
+                                               was left at {}: {}", self.path.display(), err);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        if self.is_dir {
+            let _ = rmdir_recursive(&self.path);
+        } else {
+            unsafe { CLEANUP_PATH = 0 as *const c_char; }
+            let _ = unlink(&self.path);
+        }
+    }
+}
+
+
+/// Removes temp files and staging directories for `target` left behind by
+/// earlier runs which died before they could clean up: they must carry our
+/// marker, be owned by us and name a pid which no longer exists.  Returns
+/// how many were removed.
+fn sweep_stale(target: &Path) -> IoResult<uint> {
+    let mut prefix = match target.filename() {
+        Some(name) => name.to_vec(),
+        None => return Ok(0)
+    };
+    prefix.push_all(TEMP_MARKER);
+
+    let mut removed = 0;
+    for path in try!(readdir(&target.dir_path())).iter() {
+        let name = match path.filename() {
+            Some(name) if name.starts_with(prefix.as_slice()) => name,
+            _ => continue
+        };
+        let pid = match std::str::from_utf8(name.slice_from(prefix.len())) {
+            Ok(pid) => match pid.split('.').next().unwrap().parse::<libc::pid_t>() {
+                Some(pid) => pid,
+                None => continue
+            },
+            Err(_) => continue
+        };
+        if pid == posix::getpid() || posix::process_exists(pid) {
+            continue;
+        }
+        match remove_if_ours(path) {
+            Ok(true) => removed += 1,
+            _ => ()
+        }
+    }
+    Ok(removed)
+}
+
+
+/// Writes a payload into a file, seeking over runs of zeros which the
+/// sender says were holes so that they become holes again.  Holes are
+/// only a hint: non-zero data inside one is written as usual.
+struct SparseFile<'a> {
+    file: &'a mut File,
+    holes: &'a [(u64, u64)],
+    pos: u64,
+}
+
+
+impl<'a> SparseFile<'a> {
+    fn new(file: &'a mut File, holes: &'a [(u64, u64)]) -> SparseFile<'a> {
+        SparseFile { file: file, holes: holes, pos: 0 }
+    }
+
+    /// Sets the file's length, which seeking past its end doesn't do.
+    fn finish(&mut self) -> IoResult<()> {
+        self.file.truncate(self.pos as i64)
+    }
+}
+
+
+impl<'a> Writer for SparseFile<'a> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut buf = buf;
+        while buf.len() > 0 {
+            while self.holes.len() > 0 && self.holes[0].0 + self.holes[0].1 <= self.pos {
+                self.holes = self.holes.tail();
+            }
+            let (start, length) = match self.holes.get(0) {
+                Some(&hole) => hole,
+                None => (std::u64::MAX, 0)
+            };
+
+            let n;
+            if self.pos < start {
+                n = min(buf.len() as u64, start - self.pos) as uint;
+                try!(self.file.write(buf.slice_to(n)));
+            } else {
+                n = min(buf.len() as u64, start + length - self.pos) as uint;
+                let chunk = buf.slice_to(n);
+                if chunk.iter().all(|&b| b == 0) {
+                    try!(self.file.seek(n as i64, SeekCur));
+                } else {
+                    try!(self.file.write(chunk));
+                }
+            }
+            self.pos += n as u64;
+            buf = buf.slice_from(n);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.file.flush()
+    }
+}
+
+
+/// `--tree`: the mode for the staged tree's root, so swapping it in doesn't
+/// change who can see the target.  That of the tree it replaces, or what
+/// `mkdir` would give a new directory.  An archive with a `./` entry still
+/// sets its own.
+fn tree_mode(target: &Path) -> FilePermission {
+    match lstat(target) {
+        Ok(ref stat) if stat.kind == FileType::Directory => stat.perm,
+        _ => FilePermission::from_bits_truncate(0o755 & !posix::umask())
+    }
+}
+
+
+/// `--preserve`: gives the temp file the mode and mtime of the sender's
+/// file.  Only the permission bits are kept, never setuid, setgid or
+/// sticky.
+fn apply_metadata(path: &Path, metadata: &FileMetadata) -> IoResult<()> {
+    try!(chmod(path, FilePermission::from_bits_truncate(metadata.mode & 0o777)));
+    change_file_times(path, metadata.mtime, metadata.mtime)
+}
+
+
+/// Why a run ended without committing anything.
+enum Failure {
+    Usage,
+    Lock(IoError),
+    LockBusy,
+    CheckSpace(IoError),
+    /// Bytes needed, and available if known
+    NoSpace(u64, Option<u64>),
+    CreateTemp(IoError),
+    Stream(ReliableWriteError),
+    Sync(IoError),
+    /// `--idle-timeout` ran out, with the sender's heartbeat interval if it
+    /// had been sending them
+    Stalled(Option<u32>),
+    CheckQuota(IoError),
+    TooLarge(SizeLimit),
+    Metadata(IoError),
+    UnexpectedDigest([u8; 32]),
+    UnexpectedSize(u64),
+    Unpack(IoError),
+    UnpackStatus(ProcessExit),
+    CheckTarget(IoError),
+    Precondition(Precondition),
+    PreCommit(HookError),
+    /// The temp file couldn't be read back after the pre-commit hook
+    Recheck(IoError),
+    /// The pre-commit hook changed the temp file, or replaced it
+    ChangedByHook,
+    Commit(IoError),
+    PostCommit(HookError),
+    ReceiptKey(IoError),
+}
+
+
+impl Failure {
+    fn exit_code(&self) -> int {
+        match *self {
+            Failure::Usage => exit_code::USAGE,
+            Failure::Lock(_) => exit_code::WRITE_IO,
+            Failure::LockBusy => exit_code::LOCK_BUSY,
+            Failure::CheckSpace(_) => exit_code::WRITE_IO,
+            Failure::NoSpace(..) => exit_code::NO_SPACE,
+            Failure::CreateTemp(_) => exit_code::WRITE_IO,
+            Failure::Stream(ReliableWriteError::IntegrityError) => exit_code::INTEGRITY,
+            Failure::Stream(ReliableWriteError::ProtocolError) => exit_code::PROTOCOL,
+            Failure::Stream(ReliableWriteError::TruncatedError) => exit_code::PROTOCOL,
+            Failure::Stream(ReliableWriteError::LengthError) => exit_code::PROTOCOL,
+            Failure::Stream(ReliableWriteError::ProducerError(_)) => exit_code::PRODUCER_FAILED,
+            Failure::Stream(ReliableWriteError::TimedOutError(_)) => exit_code::TIMED_OUT,
+            Failure::Stream(ReliableWriteError::ReadError(_)) => exit_code::READ_IO,
+            Failure::Stream(ReliableWriteError::WriteError(_)) => exit_code::WRITE_IO,
+            Failure::Stream(ReliableWriteError::SizeLimitError) => exit_code::SIZE_LIMIT,
+            Failure::Sync(_) => exit_code::WRITE_IO,
+            Failure::Stalled(_) => exit_code::TIMED_OUT,
+            Failure::CheckQuota(_) => exit_code::READ_IO,
+            Failure::TooLarge(_) => exit_code::SIZE_LIMIT,
+            Failure::Metadata(_) => exit_code::WRITE_IO,
+            Failure::UnexpectedDigest(_) => exit_code::UNEXPECTED_CONTENT,
+            Failure::UnexpectedSize(_) => exit_code::UNEXPECTED_CONTENT,
+            Failure::Unpack(_) => exit_code::WRITE_IO,
+            Failure::UnpackStatus(_) => exit_code::WRITE_IO,
+            Failure::CheckTarget(_) => exit_code::READ_IO,
+            Failure::Precondition(_) => exit_code::PRECONDITION_FAILED,
+            Failure::PreCommit(_) => exit_code::PRE_COMMIT_REJECTED,
+            Failure::Recheck(_) => exit_code::READ_IO,
+            Failure::ChangedByHook => exit_code::INTEGRITY,
+            Failure::Commit(_) => exit_code::COMMIT_FAILED,
+            Failure::PostCommit(_) => exit_code::POST_COMMIT_FAILED,
+            Failure::ReceiptKey(_) => exit_code::READ_IO,
+        }
+    }
+}
+
+
+impl fmt::String for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Usage => write!(f, "usage error"),
+            Failure::Lock(ref err) => write!(f, "could not lock: {}", err),
+            Failure::LockBusy => write!(f, "target is locked by another writer"),
+            Failure::CheckSpace(ref err) => write!(f, "could not check free space: {}", err),
+            Failure::NoSpace(needed, Some(available)) =>
+                write!(f, "not enough space: need {} bytes, {} available", needed, available),
+            Failure::NoSpace(needed, None) =>
+                write!(f, "not enough space: could not allocate {} bytes", needed),
+            Failure::CreateTemp(ref err) => write!(f, "could not create temp file: {}", err),
+            Failure::Stream(ref err) => write!(f, "{}", err),
+            Failure::Stalled(Some(ms)) =>
+                write!(f, "stream stalled: sender was sending heartbeats every {}ms, so it or the connection has died", ms),
+            Failure::Stalled(None) =>
+                write!(f, "stream stalled: no data within --idle-timeout, and no heartbeats to show the sender is alive"),
+            Failure::Sync(ref err) => write!(f, "could not sync to disk: {}", err),
+            Failure::CheckQuota(ref err) => write!(f, "could not measure directory for quota: {}", err),
+            Failure::TooLarge(ref limit) => write!(f, "{}", limit),
+            Failure::Metadata(ref err) => write!(f, "could not set mode or mtime: {}", err),
+            Failure::UnexpectedDigest(ref digest) =>
+                write!(f, "payload has unexpected sha256 {}", to_hex(digest.as_slice())),
+            Failure::UnexpectedSize(size) =>
+                write!(f, "payload has unexpected size {}", size),
+            Failure::Unpack(ref err) => write!(f, "could not run tar: {}", err),
+            Failure::UnpackStatus(ref status) => write!(f, "tar failed: {}", status),
+            Failure::CheckTarget(ref err) => write!(f, "could not read target: {}", err),
+            Failure::Precondition(ref why) => write!(f, "precondition failed: {}", why),
+            Failure::PreCommit(ref err) => write!(f, "rejected by pre-commit hook: {}", err),
+            Failure::Recheck(ref err) => write!(f, "could not re-read temp file after pre-commit hook: {}", err),
+            Failure::ChangedByHook => write!(f, "pre-commit hook changed the temp file"),
+            Failure::Commit(ref err) => write!(f, "commit failed: {}", err),
+            Failure::PostCommit(ref err) => write!(f, "committed, but post-commit hook failed: {}", err),
+            Failure::ReceiptKey(ref err) => write!(f, "could not read receipt key: {}", err),
+        }
+    }
+}
+
+
+/// What limits the size of the payload we'll accept.
+#[derive(Clone, Copy)]
+enum SizeLimit {
+    /// `--max-size`
+    MaxSize(u64),
+    /// `--quota`, and the bytes already used in the target's directory
+    Quota(u64, u64),
+}
+
+
+impl SizeLimit {
+    /// The largest payload this allows.
+    fn bytes(&self) -> u64 {
+        match *self {
+            SizeLimit::MaxSize(max) => max,
+            SizeLimit::Quota(quota, used) if used < quota => quota - used,
+            SizeLimit::Quota(..) => 0,
+        }
+    }
+}
+
+
+impl fmt::String for SizeLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SizeLimit::MaxSize(max) =>
+                write!(f, "payload exceeds --max-size of {} bytes", max),
+            SizeLimit::Quota(quota, used) =>
+                write!(f, "payload exceeds directory quota of {} bytes, {} of which are used", quota, used),
+        }
+    }
+}
+
+
+/// How the target differed from what `--if-match` or `--if-absent` asked for.
+enum Precondition {
+    Exists,
+    Missing,
+    Changed([u8; 32]),
+}
+
+
+impl fmt::String for Precondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Precondition::Exists => write!(f, "target exists"),
+            Precondition::Missing => write!(f, "target does not exist"),
+            Precondition::Changed(ref digest) =>
+                write!(f, "target has sha256 {}", to_hex(digest.as_slice())),
+        }
+    }
+}
+
+
+/// How a hook command failed.
+enum HookError {
+    Spawn(IoError),
+    Status(ProcessExit),
+}
+
+
+impl fmt::String for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HookError::Spawn(ref err) => write!(f, "could not run: {}", err),
+            HookError::Status(ref status) => write!(f, "{}", status),
+        }
+    }
+}
+
+
+/// Runs a hook with `sh -c`.  The hook gets no stdin and its stdout goes
+/// to our stderr, leaving our own stdio to the stream.  Everything it
+/// needs to know arrives in `RELIABLE_WRITE_*` environment variables.
+fn run_hook(cmd: &[u8], env: &[(&str, Vec<u8>)]) -> Result<(), HookError> {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    for &(ref key, ref value) in env.iter() {
+        command.env(*key, value.as_slice());
+    }
+    command.stdin(Ignored);
+    command.stdout(InheritFd(libc::STDERR_FILENO));
+    command.stderr(InheritFd(libc::STDERR_FILENO));
+    match command.status() {
+        Ok(ExitStatus(0)) => Ok(()),
+        Ok(status) => Err(HookError::Status(status)),
+        Err(err) => Err(HookError::Spawn(err))
+    }
+}
+
+
+/// The environment common to every hook, with the temp file only while
+/// it still exists.
+fn hook_env(opts: &Options, temp: Option<&Path>) -> Vec<(&'static str, Vec<u8>)> {
+    let mut env = vec![("RELIABLE_WRITE_TARGET", opts.target.as_vec().to_vec())];
+    match temp {
+        Some(temp) => env.push(("RELIABLE_WRITE_TEMP", temp.as_vec().to_vec())),
+        None => ()
+    }
+    env
+}
+
+
+/// `--pre-commit`: runs against the verified temp file (or staging
+/// directory), and vetoes the commit by failing.
+fn pre_commit(opts: &Options, temp: &Path, digest: &[u8; 32], size: u64) -> Result<(), Failure> {
+    let cmd = match opts.pre_commit {
+        Some(ref cmd) => cmd,
+        None => return Ok(())
+    };
+    let mut env = hook_env(opts, Some(temp));
+    env.push(("RELIABLE_WRITE_SHA256", to_hex(digest.as_slice()).into_bytes()));
+    env.push(("RELIABLE_WRITE_SIZE", size.to_string().into_bytes()));
+    match run_hook(cmd.as_slice(), env.as_slice()) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(Failure::PreCommit(err))
+    }
+}
+
+
+/// `--pre-commit`: the hook could have written to the temp file, or put
+/// another file in its place, so what is about to be committed is read
+/// back through its name and checked against the stream's digest.
+/// `output` is the temp file as we wrote it.
+fn recheck_temp(temp: &Path, output: &File, digest: &[u8; 32]) -> Result<(), Failure> {
+    let written = match output.stat() {
+        Ok(stat) => stat,
+        Err(err) => return Err(Failure::Recheck(err))
+    };
+    let mut current = match File::open(temp) {
+        Ok(current) => current,
+        Err(err) => return Err(Failure::Recheck(err))
+    };
+    match current.stat() {
+        Ok(ref stat) if stat.unstable.device == written.unstable.device
+                        && stat.unstable.inode == written.unstable.inode => (),
+        Ok(_) => return Err(Failure::ChangedByHook),
+        Err(err) => return Err(Failure::Recheck(err))
+    }
+    match sha256_of(&mut current) {
+        Ok(ref rehashed) if rehashed.as_slice() == digest.as_slice() => Ok(()),
+        Ok(_) => Err(Failure::ChangedByHook),
+        Err(err) => Err(Failure::Recheck(err))
+    }
+}
+
+
+/// `--post-commit`: runs once the payload is in place, e.g. to reload a
+/// service.  The temp file has become the target by then.
+fn post_commit(opts: &Options, digest: &[u8; 32], size: u64) -> Result<(), Failure> {
+    let cmd = match opts.post_commit {
+        Some(ref cmd) => cmd,
+        None => return Ok(())
+    };
+    let mut env = hook_env(opts, None);
+    env.push(("RELIABLE_WRITE_SHA256", to_hex(digest.as_slice()).into_bytes()));
+    env.push(("RELIABLE_WRITE_SIZE", size.to_string().into_bytes()));
+    match run_hook(cmd.as_slice(), env.as_slice()) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(Failure::PostCommit(err))
+    }
+}
+
+
+/// `--on-abort`: runs after a failed transfer has been cleaned up, so with
+/// no temp file, with the exit status we're about to return and its
+/// diagnostic.  Its own failure is only reported.
+fn on_abort(opts: &Options, failure: &Failure) {
+    let cmd = match opts.on_abort {
+        Some(ref cmd) => cmd,
+        None => return
+    };
+    let mut env = hook_env(opts, None);
+    env.push(("RELIABLE_WRITE_STATUS", failure.exit_code().to_string().into_bytes()));
+    env.push(("RELIABLE_WRITE_ERROR", format!("{}", failure).into_bytes()));
+    match run_hook(cmd.as_slice(), env.as_slice()) {
+        Ok(()) => (),
+        Err(err) => {
+            let mut stderr = stderr();
+            let _ = writeln!(&mut stderr, "reliable-write: warning: on-abort hook failed: {}", err);
+        }
+    }
+}
+
+
+/// What `--lock` takes its lock on.
+enum LockKind {
+    /// `<target>.lock`
+    File,
+    /// The directory containing the target
+    Dir,
+}
+
+
+struct Options {
+    sweep: bool,
+    preserve: bool,
+    lock: Option<LockKind>,
+    /// How long to wait for the lock in milliseconds, or forever if `None`
+    lock_wait_ms: Option<u64>,
+    tree: bool,
+    keep_old: bool,
+    expect_sha256: Option<[u8; 32]>,
+    expect_size: Option<u64>,
+    /// Free space to leave on the target's filesystem, in bytes
+    reserve: u64,
+    /// `--idle-timeout`: how long the stream may stall, in milliseconds
+    idle_timeout_ms: Option<u64>,
+    max_piece_size: Option<uint>,
+    /// `--lenient`: skip records we don't understand
+    lenient: bool,
+    /// `--pipeline`: read, decode and write on separate threads
+    pipeline: bool,
+    max_size: Option<u64>,
+    /// `--quota`: the most the target's directory may hold, in bytes
+    quota: Option<u64>,
+    /// `--receipt`: sync to disk, and report back on stdout
+    receipt: bool,
+    /// `--receipt-key`: the file holding the key to sign the receipt with
+    receipt_key: Option<Path>,
+    if_match: Option<[u8; 32]>,
+    if_absent: bool,
+    pre_commit: Option<Vec<u8>>,
+    post_commit: Option<Vec<u8>>,
+    on_abort: Option<Vec<u8>>,
+    /// `--log`: where to append a line about each transfer
+    log: Option<Path>,
+    target: Path,
+}
+
+
+fn arg_str(arg: Option<&Vec<u8>>) -> Option<&str> {
+    arg.and_then(|arg| std::str::from_utf8(arg.as_slice()).ok())
+}
+
+
+fn parse_args(args: &[Vec<u8>]) -> Option<Options> {
+    let mut sweep = false;
+    let mut preserve = false;
+    let mut lock = None;
+    let mut lock_wait_ms = None;
+    let mut tree = false;
+    let mut keep_old = false;
+    let mut expect_sha256 = None;
+    let mut expect_size = None;
+    let mut reserve = 0;
+    let mut if_match = None;
+    let mut if_absent = false;
+    let mut pre_commit = None;
+    let mut post_commit = None;
+    let mut on_abort = None;
+    let mut log = None;
+    let mut idle_timeout_ms = None;
+    let mut max_piece_size = None;
+    let mut lenient = false;
+    let mut pipeline = false;
+    let mut max_size = None;
+    let mut quota = None;
+    let mut receipt = false;
+    let mut receipt_key = None;
+    let mut positional = Vec::new();
+
+    let rest = args.tail();
+    let mut i = 0;
+    while i < rest.len() {
+        let arg = rest[i].as_slice();
+        i += 1;
+        if arg == b"--sweep-stale" {
+            sweep = true;
+        } else if arg == b"--preserve" {
+            preserve = true;
+        } else if arg == b"--lock" {
+            lock = Some(LockKind::File);
+        } else if arg == b"--lock-dir" {
+            lock = Some(LockKind::Dir);
+        } else if arg == b"--lock-wait" {
+            lock_wait_ms = Some(match arg_str(rest.get(i)).and_then(|v| v.parse::<u64>()) {
+                Some(secs) => match secs.checked_mul(1000) {
+                    Some(ms) => ms,
+                    None => return None
+                },
+                None => return None
+            });
+            i += 1;
+        } else if arg == b"--lock-nowait" {
+            lock_wait_ms = Some(0);
+        } else if arg == b"--tree" {
+            tree = true;
+        } else if arg == b"--keep-old" {
+            keep_old = true;
+        } else if arg == b"--expect-sha256" {
+            expect_sha256 = Some(match arg_str(rest.get(i)).and_then(digest_from_hex) {
+                Some(digest) => digest,
+                None => return None
+            });
+            i += 1;
+        } else if arg == b"--expect-size" {
+            expect_size = Some(match arg_str(rest.get(i)).and_then(|v| v.parse::<u64>()) {
+                Some(size) => size,
+                None => return None
+            });
+            i += 1;
+        } else if arg == b"--reserve" {
+            reserve = match arg_str(rest.get(i)).and_then(|v| v.parse::<u64>()) {
+                Some(bytes) => bytes,
+                None => return None
+            };
+            i += 1;
+        } else if arg == b"--if-match" {
+            if_match = Some(match arg_str(rest.get(i)).and_then(digest_from_hex) {
+                Some(digest) => digest,
+                None => return None
+            });
+            i += 1;
+        } else if arg == b"--idle-timeout" {
+            idle_timeout_ms = Some(match arg_str(rest.get(i)).and_then(|v| v.parse::<u64>()) {
+                Some(secs) => match secs.checked_mul(1000) {
+                    Some(ms) => ms,
+                    None => return None
+                },
+                None => return None
+            });
+            i += 1;
+        } else if arg == b"--max-piece-size" {
+            max_piece_size = Some(match arg_str(rest.get(i)).and_then(|v| v.parse::<uint>()) {
+                Some(size) => size,
+                None => return None
+            });
+            i += 1;
+        } else if arg == b"--max-size" || arg == b"--quota" {
+            let bytes = match arg_str(rest.get(i)).and_then(|v| v.parse::<u64>()) {
+                Some(bytes) => Some(bytes),
+                None => return None
+            };
+            if arg == b"--max-size" {
+                max_size = bytes;
+            } else {
+                quota = bytes;
+            }
+            i += 1;
+        } else if arg == b"--receipt" {
+            receipt = true;
+        } else if arg == b"--receipt-key" {
+            receipt_key = match rest.get(i) {
+                Some(path) => Some(Path::new(path.clone())),
+                None => return None
+            };
+            receipt = true;
+            i += 1;
+        } else if arg == b"--lenient" {
+            lenient = true;
+        } else if arg == b"--pipeline" {
+            pipeline = true;
+        } else if arg == b"--if-absent" {
+            if_absent = true;
+        } else if arg == b"--log" {
+            log = match rest.get(i) {
+                Some(path) => Some(Path::new(path.clone())),
+                None => return None
+            };
+            i += 1;
+        } else if arg == b"--pre-commit" || arg == b"--post-commit" || arg == b"--on-abort" {
+            let cmd = match rest.get(i) {
+                Some(cmd) => cmd.clone(),
+                None => return None
+            };
+            i += 1;
+            if arg == b"--pre-commit" {
+                pre_commit = Some(cmd);
+            } else if arg == b"--post-commit" {
+                post_commit = Some(cmd);
+            } else {
+                on_abort = Some(cmd);
+            }
+        } else {
+            positional.push(arg);
+        }
+    }
+    // The sender's file metadata has nothing to say about an archive.
+    if positional.len() != 1 || (keep_old && !tree) || (preserve && tree) {
+        return None;
+    }
+    if if_match.is_some() && (if_absent || tree) {
+        return None;
+    }
+    // The precondition check and the rename must happen under the lock.
+    if (lock_wait_ms.is_some() || if_match.is_some() || if_absent) && lock.is_none() {
+        lock = Some(LockKind::File);
+    }
+    Some(Options {
+        sweep: sweep,
+        preserve: preserve,
+        lock: lock,
+        lock_wait_ms: lock_wait_ms,
+        tree: tree,
+        keep_old: keep_old,
+        expect_sha256: expect_sha256,
+        expect_size: expect_size,
+        reserve: reserve,
+        idle_timeout_ms: idle_timeout_ms,
+        max_piece_size: max_piece_size,
+        lenient: lenient,
+        pipeline: pipeline,
+        max_size: max_size,
+        quota: quota,
+        receipt: receipt,
+        receipt_key: receipt_key,
+        if_match: if_match,
+        if_absent: if_absent,
+        pre_commit: pre_commit,
+        post_commit: post_commit,
+        on_abort: on_abort,
+        log: log,
+        target: Path::new(positional[0].clone()),
+    })
+}
 
 
 fn print_usage(program: &[u8]) {
     let mut stderr = stderr();
     let mut output = Vec::new();
     output.extend(program.iter().map(|x| x.clone()));
-    output.extend(b" filename\n".iter().map(|x| x.clone()));
-    assert!(stderr.write(output.as_slice()).is_ok());
+    output.extend(b" [options] filename\n".iter().map(|x| x.clone()));
+    output.extend(program.iter().map(|x| x.clone()));
+    output.extend(b" [options] --tree [--keep-old] directory\n".iter().map(|x| x.clone()));
+    output.extend(b"\noptions:\n".iter().map(|x| x.clone()));
+    output.extend(b"  --sweep-stale        remove temp files left by dead writers\n".iter().map(|x| x.clone()));
+    output.extend(b"  --preserve           keep the sender's file mode and mtime, if sent\n".iter().map(|x| x.clone()));
+    output.extend(b"  --lock               hold a lock on <target>.lock for the whole transfer\n".iter().map(|x| x.clone()));
+    output.extend(b"  --lock-dir           lock the target's directory instead\n".iter().map(|x| x.clone()));
+    output.extend(b"  --lock-wait SECONDS  give up if the lock is held for longer than this\n".iter().map(|x| x.clone()));
+    output.extend(b"  --lock-nowait        give up at once if the lock is held\n".iter().map(|x| x.clone()));
+    output.extend(b"  --expect-sha256 HEX  only commit a payload with this digest\n".iter().map(|x| x.clone()));
+    output.extend(b"  --expect-size N      only commit a payload of N bytes\n".iter().map(|x| x.clone()));
+    output.extend(b"  --reserve BYTES      refuse payloads which would leave less free space\n".iter().map(|x| x.clone()));
+    output.extend(b"  --idle-timeout SECS  give up if the stream stalls for this long\n".iter().map(|x| x.clone()));
+    output.extend(b"  --max-piece-size N   accept pieces of up to N bytes (default 262144)\n".iter().map(|x| x.clone()));
+    output.extend(b"  --max-size BYTES     refuse payloads larger than this\n".iter().map(|x| x.clone()));
+    output.extend(b"  --quota BYTES        refuse payloads which would take the target's directory\n".iter().map(|x| x.clone()));
+    output.extend(b"                       over this size, not counting the target itself\n".iter().map(|x| x.clone()));
+    output.extend(b"  --lenient            skip stream records this version doesn't know\n".iter().map(|x| x.clone()));
+    output.extend(b"  --pipeline           read, check and write the stream on separate threads\n".iter().map(|x| x.clone()));
+    output.extend(b"  --if-match HEX       only replace the target if its sha256 is HEX\n".iter().map(|x| x.clone()));
+    output.extend(b"  --if-absent          only create the target, never replace it\n".iter().map(|x| x.clone()));
+    output.extend(b"  --pre-commit CMD     veto the commit unless CMD succeeds on the temp file\n".iter().map(|x| x.clone()));
+    output.extend(b"  --post-commit CMD    run CMD after committing\n".iter().map(|x| x.clone()));
+    output.extend(b"  --on-abort CMD       run CMD after a failed transfer\n".iter().map(|x| x.clone()));
+    output.extend(b"  --receipt            sync the result to disk, then write a receipt on stdout\n".iter().map(|x| x.clone()));
+    output.extend(b"  --receipt-key FILE   sign the receipt with the key in FILE; implies --receipt\n".iter().map(|x| x.clone()));
+    output.extend(b"  --log PATH           append a line to PATH saying how the transfer went\n".iter().map(|x| x.clone()));
+    let _ = stderr.write(output.as_slice());
 }
 
 
-fn main() {
-    let args = os::args_as_bytes();
+/// `--lock`: serialises concurrent writers of the same target for the
+/// duration of the transfer.
+fn acquire_lock(opts: &Options) -> Result<Option<FileLock>, Failure> {
+    let lock = match opts.lock {
+        None => return Ok(None),
+        Some(LockKind::File) => FileLock::open(&with_suffix(&opts.target, LOCK_SUFFIX), false),
+        Some(LockKind::Dir) => FileLock::open(&opts.target.dir_path(), true),
+    };
+    let lock = match lock {
+        Ok(lock) => lock,
+        Err(err) => return Err(Failure::Lock(err))
+    };
+
+    let wait_ms = match opts.lock_wait_ms {
+        Some(ms) => ms,
+        None => return match lock.lock() {
+            Ok(()) => Ok(Some(lock)),
+            Err(err) => Err(Failure::Lock(err))
+        }
+    };
+    let mut waited = 0;
+    loop {
+        match lock.try_lock() {
+            Ok(true) => return Ok(Some(lock)),
+            Ok(false) if waited >= wait_ms => return Err(Failure::LockBusy),
+            Ok(false) => {
+                sleep(Duration::milliseconds(LOCK_POLL_MS as i64));
+                waited += LOCK_POLL_MS;
+            },
+            Err(err) => return Err(Failure::Lock(err))
+        }
+    }
+}
 
-    let program_name = args[0].as_slice().clone();
-    if args.len() < 2 {
-        print_usage(program_name);
-        os::set_exit_status(1);
-        return;
+
+/// `--if-match`, `--if-absent`: checks the target is still in the state the
+/// sender based its upload on.  This happens under the lock, both before
+/// the transfer starts and again just before committing.
+fn check_precondition(opts: &Options) -> Result<(), Failure> {
+    if opts.if_absent && opts.target.exists() {
+        return Err(Failure::Precondition(Precondition::Exists));
     }
-    let output_path = Path::new(args[1].as_slice().clone());
-    let output_path_tmp = Path::new(output_path.clone().into_vec() + b".tmp");
+    let expected = match opts.if_match {
+        Some(ref expected) => expected,
+        None => return Ok(())
+    };
+    let mut current = match File::open(&opts.target) {
+        Ok(current) => current,
+        Err(IoError { kind: FileNotFound, .. }) =>
+            return Err(Failure::Precondition(Precondition::Missing)),
+        Err(err) => return Err(Failure::CheckTarget(err))
+    };
+    match sha256_of(&mut current) {
+        Ok(ref digest) if digest.as_slice() == expected.as_slice() => Ok(()),
+        Ok(digest) => Err(Failure::Precondition(Precondition::Changed(digest))),
+        Err(err) => Err(Failure::CheckTarget(err))
+    }
+}
 
-    let mut input = stdin();
-    let mut output = match File::open_mode(&output_path_tmp, Open, Write) {
-        Ok(f) => f,
-        Err(e) => panic!("file error: {}", e),
+
+/// Checks a payload of the `declared` size fits on the target's filesystem
+/// with `--reserve` bytes to spare, before any of it is read.  Without a
+/// declared size, only the `--reserve` headroom can be checked.
+fn check_space(opts: &Options, declared: Option<u64>) -> Result<(), Failure> {
+    let size = match (declared, opts.reserve) {
+        (Some(size), _) => size,
+        (None, 0) => return Ok(()),
+        (None, _) => 0
+    };
+    let available = match posix::available_space(&opts.target.dir_path()) {
+        Ok(available) => available,
+        Err(err) => return Err(Failure::CheckSpace(err))
     };
+    // The size comes from the sender, so it may be anything.
+    match size.checked_add(opts.reserve) {
+        Some(needed) if needed <= available => Ok(()),
+        Some(needed) => Err(Failure::NoSpace(needed, Some(available))),
+        None => Err(Failure::NoSpace(size, Some(available)))
+    }
+}
 
-    match copy_out(&mut input, &mut output) {
-        Ok(_) => {
-            // is `output' flushed at this point in time?
-            assert!(rename(&output_path_tmp, &output_path).is_ok())
+
+/// Refuses payloads other than the one pinned with `--expect-sha256` and
+/// `--expect-size`.
+fn check_expected(opts: &Options, digest: &[u8; 32], size: u64) -> Result<(), Failure> {
+    match opts.expect_size {
+        Some(expected) if expected != size => return Err(Failure::UnexpectedSize(size)),
+        _ => ()
+    }
+    match opts.expect_sha256 {
+        Some(ref expected) if expected.as_slice() != digest.as_slice() =>
+            Err(Failure::UnexpectedDigest(*digest)),
+        _ => Ok(())
+    }
+}
+
+
+/// Every path under `dir` with its `lstat`, found without following
+/// symlinks.  `skip`, and anything under it, is left out.  Paths which
+/// vanish while we look are left out too.
+fn lstat_tree(dir: &Path, skip: Option<&Path>) -> IoResult<Vec<(Path, FileStat)>> {
+    let mut found = Vec::new();
+    let mut pending = try!(readdir(dir));
+    loop {
+        let path = match pending.pop() {
+            Some(path) => path,
+            None => break
+        };
+        if skip.map_or(false, |skip| *skip == path) {
+            continue;
+        }
+        let stat = match lstat(&path) {
+            Ok(stat) => stat,
+            Err(IoError { kind: FileNotFound, .. }) => continue,
+            Err(err) => return Err(err)
+        };
+        if stat.kind == FileType::Directory {
+            match readdir(&path) {
+                Ok(children) => pending.extend(children.into_iter()),
+                Err(IoError { kind: FileNotFound, .. }) => continue,
+                Err(err) => return Err(err)
+            }
+        }
+        found.push((path, stat));
+    }
+    Ok(found)
+}
+
+
+/// `--tree --receipt`: syncs every file and directory tar unpacked, before
+/// the tree is swapped into place.
+fn sync_tree(dir: &Path) -> Result<(), Failure> {
+    let entries = match lstat_tree(dir, None) {
+        Ok(entries) => entries,
+        Err(err) => return Err(Failure::Sync(err))
+    };
+    let mut to_sync = vec![dir.clone()];
+    for (path, stat) in entries.into_iter() {
+        if stat.kind == FileType::RegularFile || stat.kind == FileType::Directory {
+            to_sync.push(path);
+        }
+    }
+    for path in to_sync.iter() {
+        match File::open(path).and_then(|mut file| file.fsync()) {
+            Ok(()) => (),
+            Err(err) => return Err(Failure::Sync(err))
+        }
+    }
+    Ok(())
+}
+
+
+/// `--receipt`: makes the rename which committed the payload durable, by
+/// syncing the target's directory.  A tree's contents are synced by
+/// `sync_tree` before it is committed.
+fn sync_rename(opts: &Options) -> Result<(), Failure> {
+    if !opts.receipt {
+        return Ok(());
+    }
+    match File::open(&opts.target.dir_path()).and_then(|mut dir| dir.fsync()) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(Failure::Sync(err))
+    }
+}
+
+
+/// `--receipt`: tells the sender, on our stdout, what became of its
+/// stream.  Sent whether or not we committed it, and signed with `key`
+/// for the stream's `nonce` if there is one.
+fn send_receipt(opts: &Options, key: Option<&ReceiptKey>, nonce: Option<&[u8; 16]>,
+                result: &Result<([u8; 32], u64), Failure>) -> IoResult<()> {
+    let (status, sha256, size) = match *result {
+        Ok((digest, size)) => (exit_code::SUCCESS, digest, size),
+        Err(ref failure) => (failure.exit_code(), [0u8; 32], 0),
+    };
+    let mut receipt = CommitReceipt {
+        status: status as u32,
+        size: size,
+        sha256: sha256,
+        path: opts.target.as_vec().to_vec(),
+        mac: None,
+    };
+    match key {
+        Some(key) => receipt.sign(key, nonce),
+        None => ()
+    }
+    receipt.write_to(&mut stdout())
+}
+
+
+/// `--quota`: the bytes in regular files under the target's directory,
+/// except for the target, which the payload will replace.  Symlinks aren't
+/// followed, so a link to `/` or a loop of links costs nothing, but the
+/// whole subtree is walked on every run, however large it is.
+fn directory_usage(target: &Path) -> IoResult<u64> {
+    let mut used = 0;
+    for &(_, ref stat) in try!(lstat_tree(&target.dir_path(), Some(target))).iter() {
+        if stat.kind == FileType::RegularFile {
+            used += stat.size;
+        }
+    }
+    Ok(used)
+}
+
+
+/// The tightest of `--max-size` and `--quota`, if either was given.
+fn size_limit(opts: &Options) -> Result<Option<SizeLimit>, Failure> {
+    let quota = match opts.quota {
+        Some(quota) => match directory_usage(&opts.target) {
+            Ok(used) => Some(SizeLimit::Quota(quota, used)),
+            Err(err) => return Err(Failure::CheckQuota(err))
         },
-        Err(ReliableWriteError::IntegrityError) => {
-            assert!(unlink(&output_path_tmp).is_ok());
-            panic!("IntegrityError");
+        None => None
+    };
+    let max_size = opts.max_size.map(|max| SizeLimit::MaxSize(max));
+    Ok(match (max_size, quota) {
+        (Some(max_size), Some(quota)) =>
+            Some(if max_size.bytes() <= quota.bytes() { max_size } else { quota }),
+        (max_size, None) => max_size,
+        (None, quota) => quota,
+    })
+}
+
+
+/// Turns a decoding error into the reason the transfer failed, given
+/// the sender's heartbeat interval and our size limit.
+fn stream_failure(err: ReliableWriteError, heartbeat: Option<u32>, limit: Option<SizeLimit>) -> Failure {
+    match (err, limit) {
+        (ReliableWriteError::TimedOutError(_), _) => Failure::Stalled(heartbeat),
+        (ReliableWriteError::SizeLimitError, Some(limit)) => Failure::TooLarge(limit),
+        (err, _) => Failure::Stream(err)
+    }
+}
+
+
+fn decap_options(opts: &Options, limit: Option<SizeLimit>) -> DecapOptions {
+    let mut options = DecapOptions::new();
+    options.max_size(limit.map(|limit| limit.bytes()));
+    options.strict(!opts.lenient);
+    options.idle_timeout(opts.idle_timeout_ms);
+    match opts.max_piece_size {
+        Some(size) => { options.max_piece_size(size); },
+        None => ()
+    }
+    options
+}
+
+
+/// Our stdin, through `--idle-timeout`.  The buffering has to go on top,
+/// where it can't hide data from the timeout's `poll`.  With `--pipeline`
+/// it is read ahead on another thread, which buffers it too.
+fn open_input(opts: &Options, options: &DecapOptions) -> Box<Reader + 'static> {
+    let input = options.timeout_reader(stdin_raw(), libc::STDIN_FILENO);
+    if opts.pipeline {
+        Box::new(ReadAhead::new(input, pipeline::DEFAULT_DEPTH)) as Box<Reader>
+    } else {
+        Box::new(BufferedReader::new(input)) as Box<Reader>
+    }
+}
+
+
+/// Decodes the payload into `output`, through a thread of its own with
+/// `--pipeline`, and hands `output` back once it has all been written.
+fn copy_payload(decap: &mut ReliableDecap, mut output: File, opts: &Options)
+                -> ReliableWriteResult<([u8; 32], File)> {
+    if !opts.pipeline {
+        let digest = try!(decap.copy_to(&mut output));
+        return Ok((digest, output));
+    }
+    let mut output = WriteBehind::new(output, pipeline::DEFAULT_DEPTH);
+    let digest = try!(decap.copy_to(&mut output));
+    match output.finish() {
+        Ok(output) => Ok((digest, output)),
+        Err(err) => Err(ReliableWriteError::WriteError(err))
+    }
+}
+
+
+fn write_file(opts: &Options, limit: Option<SizeLimit>, nonce: &mut Option<[u8; 16]>)
+              -> Result<([u8; 32], u64), Failure> {
+    let options = decap_options(opts, limit);
+    let mut input = open_input(opts, &options);
+    let mut decap = match options.decap(&mut *input) {
+        Ok(decap) => decap,
+        Err(err) => return Err(stream_failure(err, None, limit))
+    };
+    let header = decap.header().clone();
+    *nonce = header.receipt_nonce;
+    try!(check_space(opts, header.declared_size));
+
+    let (temp, mut output) = match TempFile::create(&opts.target) {
+        Ok(pair) => pair,
+        Err(err) => return Err(Failure::CreateTemp(err))
+    };
+
+    // Returning early drops `temp', which unlinks the file.
+    let copied = match header.declared_size {
+        // Preallocating would fill in the holes we're about to make.
+        // Punching them needs the file itself, so there's no write-behind.
+        Some(_) if header.holes.len() > 0 => {
+            let copied = {
+                let mut sparse = SparseFile::new(&mut output, header.holes.as_slice());
+                match decap.copy_to(&mut sparse) {
+                    Ok(digest) => match sparse.finish() {
+                        Ok(()) => Ok(digest),
+                        Err(err) => Err(ReliableWriteError::WriteError(err))
+                    },
+                    Err(err) => Err(err)
+                }
+            };
+            copied.map(move |digest| (digest, output))
+        },
+        Some(size) => {
+            match posix::preallocate(output.as_raw_fd(), size) {
+                Ok(Preallocation::NoSpace) => return Err(Failure::NoSpace(size, None)),
+                Ok(_) => (),
+                Err(err) => return Err(Failure::CreateTemp(err))
+            }
+            copy_payload(&mut decap, output, opts)
         },
-        Err(ReliableWriteError::ProtocolError) => {
-            assert!(unlink(&output_path_tmp).is_ok());
-            panic!("ProtocolError");
+        None => copy_payload(&mut decap, output, opts)
+    };
+    let (digest, mut output) = match copied {
+        Ok(copied) => copied,
+        Err(err) => return Err(stream_failure(err, decap.heartbeat_interval(), limit))
+    };
+    let size = decap.payload_length();
+    try!(check_expected(opts, &digest, size));
+    match header.metadata {
+        Some(ref metadata) if opts.preserve => match apply_metadata(&temp.path, metadata) {
+            Ok(()) => (),
+            Err(err) => return Err(Failure::Metadata(err))
         },
-        Err(ReliableWriteError::ReadError(err)) => {
-            assert!(unlink(&output_path_tmp).is_ok());
-            panic!("ReadError: {}", err);
+        _ => ()
+    }
+    try!(pre_commit(opts, &temp.path, &digest, size));
+    if opts.pre_commit.is_some() {
+        try!(recheck_temp(&temp.path, &output, &digest));
+    }
+    try!(check_precondition(opts));
+    // is `output' flushed at this point in time?
+    if opts.receipt {
+        match output.fsync() {
+            Ok(()) => (),
+            Err(err) => return Err(Failure::Sync(err))
+        }
+    }
+    match temp.commit(&opts.target, opts.if_absent) {
+        Ok(()) => (),
+        Err(IoError { kind: PathAlreadyExists, .. }) =>
+            return Err(Failure::Precondition(Precondition::Exists)),
+        Err(err) => return Err(Failure::Commit(err))
+    }
+    try!(sync_rename(opts));
+    try!(post_commit(opts, &digest, size));
+    Ok((digest, size))
+}
+
+
+/// `--tree`: the payload is a tar archive, unpacked as it arrives into a
+/// staging directory next to the target.  Nothing is swapped in until the
+/// stream has verified and tar has exited cleanly.
+fn write_tree(opts: &Options, limit: Option<SizeLimit>, nonce: &mut Option<[u8; 16]>)
+              -> Result<([u8; 32], u64), Failure> {
+    let options = decap_options(opts, limit);
+    let mut input = open_input(opts, &options);
+    let mut decap = match options.decap(&mut *input) {
+        Ok(decap) => decap,
+        Err(err) => return Err(stream_failure(err, None, limit))
+    };
+    *nonce = decap.header().receipt_nonce;
+    try!(check_space(opts, decap.header().declared_size));
+
+    let temp = match TempFile::create_dir(&opts.target) {
+        Ok(temp) => temp,
+        Err(err) => return Err(Failure::CreateTemp(err))
+    };
+    match chmod(&temp.path, tree_mode(&opts.target)) {
+        Ok(()) => (),
+        Err(err) => return Err(Failure::CreateTemp(err))
+    }
+
+    let mut command = Command::new("tar");
+    command.arg("-x").arg("-f").arg("-").arg("-C").arg(&temp.path);
+    command.stdout(InheritFd(libc::STDERR_FILENO));
+    command.stderr(InheritFd(libc::STDERR_FILENO));
+    let mut tar = match command.spawn() {
+        Ok(tar) => tar,
+        Err(err) => return Err(Failure::Unpack(err))
+    };
+
+    let copied = decap.copy_to(tar.stdin.as_mut().unwrap());
+    let size = decap.payload_length();
+    drop(tar.stdin.take());
+    let digest = match copied {
+        Ok(digest) => digest,
+        Err(err) => {
+            let _ = tar.signal_kill();
+            let _ = tar.wait();
+            return Err(stream_failure(err, decap.heartbeat_interval(), limit));
+        }
+    };
+    match tar.wait() {
+        Ok(ExitStatus(0)) => (),
+        Ok(status) => return Err(Failure::UnpackStatus(status)),
+        Err(err) => return Err(Failure::Unpack(err))
+    }
+    try!(check_expected(opts, &digest, size));
+    try!(pre_commit(opts, &temp.path, &digest, size));
+    if opts.receipt {
+        try!(sync_tree(&temp.path));
+    }
+    try!(check_precondition(opts));
+
+    match temp.commit_tree(&opts.target, opts.keep_old, opts.if_absent) {
+        Ok(()) => (),
+        Err(IoError { kind: PathAlreadyExists, .. }) if opts.if_absent =>
+            return Err(Failure::Precondition(Precondition::Exists)),
+        Err(err) => return Err(Failure::Commit(err))
+    }
+    try!(sync_rename(opts));
+    try!(post_commit(opts, &digest, size));
+    Ok((digest, size))
+}
+
+
+/// The stderr tail the producer's status came with, if any.
+fn producer_stderr(failure: &Failure) -> Option<&[u8]> {
+    match *failure {
+        Failure::Stream(ReliableWriteError::ProducerError(Some(ref status)))
+            if status.stderr_tail.len() > 0 => Some(status.stderr_tail.as_slice()),
+        _ => None
+    }
+}
+
+
+/// `--log`: appends one line about the transfer to `path`, so the log
+/// explains every upload we rejected.  The line goes out in a single
+/// write, so concurrent writers' lines don't interleave.
+fn append_log(path: &Path, target: &Path, result: &Result<([u8; 32], u64), Failure>) -> IoResult<()> {
+    let mut line = format!("{} reliable-write[{}] {}: ",
+                           posix::unix_time(), posix::getpid(), target.display());
+    match *result {
+        Ok((ref digest, size)) =>
+            line.push_str(format!("committed sha256={} size={}", to_hex(digest.as_slice()), size).as_slice()),
+        Err(ref failure) => {
+            line.push_str(format!("failed with status {}: {}", failure.exit_code(), failure).as_slice());
+            match producer_stderr(failure) {
+                Some(tail) => line.push_str(format!(" stderr=\"{}\"",
+                                                    String::from_utf8_lossy(tail).escape_default()).as_slice()),
+                None => ()
+            }
+        }
+    }
+    line.push('\n');
+    let mut log = try!(File::open_mode(path, Append, Write));
+    log.write(line.as_bytes())
+}
+
+
+/// Verifies and commits the payload, and returns its SHA-256 and size.
+/// Sets `nonce` to the receipt nonce in the stream's header, if it gets
+/// that far and there is one.
+fn transfer(opts: &Options, nonce: &mut Option<[u8; 16]>) -> Result<([u8; 32], u64), Failure> {
+    // Held until we return, whichever way that is.
+    let _lock = try!(acquire_lock(opts));
+    try!(check_precondition(opts));
+    let limit = try!(size_limit(opts));
+
+    if opts.tree {
+        write_tree(opts, limit, nonce)
+    } else {
+        write_file(opts, limit, nonce)
+    }
+}
+
+
+fn run(args: &[Vec<u8>]) -> Result<(), Failure> {
+    let opts = match parse_args(args) {
+        Some(opts) => opts,
+        None => return Err(Failure::Usage)
+    };
+    let key = match opts.receipt_key {
+        Some(ref path) => match ReceiptKey::read_from(path) {
+            Ok(key) => Some(key),
+            Err(err) => return Err(Failure::ReceiptKey(err))
         },
-        Err(ReliableWriteError::WriteError(err)) => {
-            assert!(unlink(&output_path_tmp).is_ok());
-            panic!("WriteError: {}", err);
+        None => None
+    };
+
+    if opts.sweep {
+        match sweep_stale(&opts.target) {
+            Ok(_) => (),
+            Err(err) => {
+                let mut stderr = stderr();
+                let _ = writeln!(&mut stderr, "reliable-write: warning: could not sweep stale temp files: {}", err);
+            }
+        }
+    }
+
+    let mut nonce = None;
+    let result = transfer(&opts, &mut nonce);
+    match result {
+        Ok(_) | Err(Failure::PostCommit(_)) => (),
+        Err(ref failure) => on_abort(&opts, failure)
+    }
+    match opts.log {
+        Some(ref path) => match append_log(path, &opts.target, &result) {
+            Ok(()) => (),
+            Err(err) => {
+                let mut stderr = stderr();
+                let _ = writeln!(&mut stderr, "reliable-write: warning: could not write log: {}", err);
+            }
         },
+        None => ()
+    }
+    if opts.receipt {
+        match send_receipt(&opts, key.as_ref(), nonce.as_ref(), &result) {
+            Ok(()) => (),
+            Err(err) => {
+                let mut stderr = stderr();
+                let _ = writeln!(&mut stderr, "reliable-write: warning: could not send receipt: {}", err);
+            }
+        }
     }
+    result.map(|_| ())
+}
+
+
+fn main() {
+    let args = os::args_as_bytes();
+    let status = match run(args.as_slice()) {
+        Ok(()) => exit_code::SUCCESS,
+        Err(Failure::Usage) => {
+            print_usage(args[0].as_slice());
+            exit_code::USAGE
+        },
+        Err(failure) => {
+            let mut stderr = stderr();
+            let _ = writeln!(&mut stderr, "reliable-write: {}", failure);
+            match producer_stderr(&failure) {
+                Some(tail) => for line in String::from_utf8_lossy(tail).lines() {
+                    let _ = writeln!(&mut stderr, "reliable-write: producer stderr: {}", line);
+                },
+                None => ()
+            }
+            failure.exit_code()
+        }
+    };
+    os::set_exit_status(status);
 }
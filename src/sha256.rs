@@ -8,16 +8,33 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-//! This module implements only the Sha256 function since that is all that is needed for internal
-//! use. This implementation is not intended for external use or for any use where security is
-//! important.
+//! SHA-256, as used for every digest in a stream, for callers who need to hash the same data
+//! themselves, such as to work out the `--expect-sha256` of a file before sending it.
+//!
+//! `Sha256` is a `Writer`, so anything that writes can feed it, and `HashingReader` and
+//! `HashingWriter` hash whatever passes through them. `to_hex` and `digest_from_hex` convert
+//! digests to and from the form `reliable-write` prints and accepts. `Sha256::export_state`
+//! saves a hash part way through, for `Sha256::import_state` to resume.
+//!
+//! # Security
+//!
+//! The digests are standard SHA-256 and are checked against the NIST test vectors on every
+//! backend, but the code, which started out as the one in the Rust compiler, has not been
+//! audited. Use it to detect damage, accidental or otherwise, to data whose expected digest
+//! arrived by a trusted route. A digest on its own authenticates nothing: anyone who can change
+//! the data can recompute it, so use `hmac_sha256` or a signature for that. Hashed data is left
+//! in the hasher's buffer and state after use and is never wiped, keys given to `hmac_sha256`
+//! included, so keep such keys out of processes which might leak their memory.
 
 #![allow(unused_attributes)]
 #![allow(dead_code)]
 
-use std::iter::range_step;
+use libc::{c_int, size_t};
+use std::io::IoResult;
+use std::iter::{range_step, repeat};
 use std::num::Int;
 use std::slice::bytes::{MutableByteVector, copy_memory};
+use std::sync::atomic::{AtomicUint, ATOMIC_UINT_INIT, Ordering};
 
 /// Write a u32 into a vector, which must be 4 bytes long. The value is written in big-endian
 /// format.
@@ -77,7 +94,8 @@ fn add_bytes_to_bits<T: Int + ToBits>(bits: T, bytes: T) -> T {
 /// results in those bytes being marked as used by the buffer.
 trait FixedBuffer {
     /// Input a vector of bytes. If the buffer becomes full, process it with the provided
-    /// function and then clear the buffer.
+    /// function and then clear the buffer. The function is given whole blocks, as many at once
+    /// as possible.
     fn input<F>(&mut self, input: &[u8], F) where F: FnMut(&[u8]);
 
     /// Reset the buffer.
@@ -146,7 +164,7 @@ impl FixedBuffer for FixedBuffer64 {
                         self.buffer.slice_mut(self.buffer_idx, size),
                         input.slice_to(buffer_remaining));
                 self.buffer_idx = 0;
-                func(self.buffer[]);
+                func(&self.buffer[]);
                 i += buffer_remaining;
             } else {
                 copy_memory(
@@ -157,11 +175,12 @@ impl FixedBuffer for FixedBuffer64 {
             }
         }
 
-        // While we have at least a full buffer size chunk's worth of data, process that data
-        // without copying it into the buffer
-        while input.len() - i >= size {
-            func(input.slice(i, i + size));
-            i += size;
+        // Process all the full buffer size chunks of data in one go, without copying them into
+        // the buffer
+        let whole = (input.len() - i) / size * size;
+        if whole > 0 {
+            func(input.slice(i, i + whole));
+            i += whole;
         }
 
         // Copy any input data into the buffer. At this point in the method, the amount of
@@ -269,9 +288,65 @@ pub trait Digest {
     }
 }
 
+/// An implementation of the SHA-256 compression function.
+#[derive(Clone, Copy, PartialEq, Show)]
+enum Backend {
+    /// Portable Rust, in Engine256State::process_block
+    Scalar,
+    /// The x86 SHA extensions
+    ShaNi,
+    /// The ARMv8 cryptography extensions
+    Armv8,
+}
+
+extern {
+    fn reliable_rw_sha256_backend() -> c_int;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn reliable_rw_sha256_shani(state: *mut u32, data: *const u8, blocks: size_t);
+    #[cfg(target_arch = "aarch64")]
+    fn reliable_rw_sha256_armv8(state: *mut u32, data: *const u8, blocks: size_t);
+}
+
+/// The ARMv8 backend has yet to be checked against the test vectors on real hardware, so it is
+/// only used when the `armv8-sha` feature asks for it. `cargo test --features armv8-sha` on an
+/// aarch64 machine with the SHA-2 extensions runs that check.
+#[cfg(feature = "armv8-sha")]
+static ARMV8_ENABLED: bool = true;
+#[cfg(not(feature = "armv8-sha"))]
+static ARMV8_ENABLED: bool = false;
+
+/// The detected backend plus one, or zero before detection.
+static BACKEND: AtomicUint = ATOMIC_UINT_INIT;
+
+impl Backend {
+    /// The fastest backend this CPU supports. Detection happens once per process.
+    fn detect() -> Backend {
+        let mut code = BACKEND.load(Ordering::Relaxed);
+        if code == 0 {
+            code = unsafe { reliable_rw_sha256_backend() } as uint + 1;
+            BACKEND.store(code, Ordering::Relaxed);
+        }
+        match code - 1 {
+            1 => Backend::ShaNi,
+            2 if ARMV8_ENABLED => Backend::Armv8,
+            _ => Backend::Scalar
+        }
+    }
+
+    /// Every backend this CPU supports, the scalar one first.
+    fn available() -> Vec<Backend> {
+        let mut backends = vec![Backend::Scalar];
+        if Backend::detect() != Backend::Scalar {
+            backends.push(Backend::detect());
+        }
+        backends
+    }
+}
+
 // A structure that represents that state of a digest computation for the SHA-2 512 family of digest
-// functions
-#[derive(Clone)]
+// functions. The accelerated backends take it as an array of eight words.
+#[derive(Clone, Copy)]
+#[repr(C)]
 struct Engine256State {
     h0: u32,
     h1: u32,
@@ -308,6 +383,36 @@ impl Engine256State {
         self.h7 = h[7];
     }
 
+    /// Write the state out as a digest, which must be 32 bytes long.
+    fn write_digest(&self, out: &mut [u8]) {
+        write_u32_be(out.slice_mut(0, 4), self.h0);
+        write_u32_be(out.slice_mut(4, 8), self.h1);
+        write_u32_be(out.slice_mut(8, 12), self.h2);
+        write_u32_be(out.slice_mut(12, 16), self.h3);
+        write_u32_be(out.slice_mut(16, 20), self.h4);
+        write_u32_be(out.slice_mut(20, 24), self.h5);
+        write_u32_be(out.slice_mut(24, 28), self.h6);
+        write_u32_be(out.slice_mut(28, 32), self.h7);
+    }
+
+    /// Process any number of whole blocks with the given backend, which must be available.
+    fn process_blocks(&mut self, backend: Backend, data: &[u8]) {
+        assert!(data.len() % 64 == 0);
+        match backend {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::ShaNi => unsafe {
+                reliable_rw_sha256_shani(&mut self.h0, data.as_ptr(), (data.len() / 64) as size_t)
+            },
+            #[cfg(target_arch = "aarch64")]
+            Backend::Armv8 => unsafe {
+                reliable_rw_sha256_armv8(&mut self.h0, data.as_ptr(), (data.len() / 64) as size_t)
+            },
+            _ => for block in data.chunks(64) {
+                self.process_block(block);
+            }
+        }
+    }
+
     fn process_block(&mut self, data: &[u8]) {
         fn ch(x: u32, y: u32, z: u32) -> u32 {
             ((x & y) ^ ((!x) & z))
@@ -435,6 +540,7 @@ struct Engine256 {
     buffer: FixedBuffer64,
     state: Engine256State,
     finished: bool,
+    backend: Backend,
 }
 
 impl Engine256 {
@@ -443,7 +549,8 @@ impl Engine256 {
             length_bits: 0,
             buffer: FixedBuffer64::new(),
             state: Engine256State::new(h),
-            finished: false
+            finished: false,
+            backend: Backend::detect(),
         }
     }
 
@@ -459,7 +566,8 @@ impl Engine256 {
         // Assumes that input.len() can be converted to u64 without overflow
         self.length_bits = add_bytes_to_bits(self.length_bits, input.len() as u64);
         let self_state = &mut self.state;
-        self.buffer.input(input, |&mut: input: &[u8]| { self_state.process_block(input) });
+        let backend = self.backend;
+        self.buffer.input(input, |&mut: input: &[u8]| { self_state.process_blocks(backend, input) });
     }
 
     fn finish(&mut self) {
@@ -468,13 +576,34 @@ impl Engine256 {
         }
 
         let self_state = &mut self.state;
-        self.buffer.standard_padding(8, |&mut: input: &[u8]| { self_state.process_block(input) });
+        let backend = self.backend;
+        self.buffer.standard_padding(8, |&mut: input: &[u8]| { self_state.process_blocks(backend, input) });
         write_u32_be(self.buffer.next(4), (self.length_bits >> 32) as u32 );
         write_u32_be(self.buffer.next(4), self.length_bits as u32);
-        self_state.process_block(self.buffer.full_buffer());
+        self_state.process_blocks(backend, self.buffer.full_buffer());
 
         self.finished = true;
     }
+
+    /// Write the digest of the input so far to out, which must be 32 bytes long, leaving the
+    /// engine as it was so that more input may follow. Only the state words are copied; the
+    /// padding is built in a block on the stack rather than in a copy of the buffer.
+    fn finish_snapshot(&self, out: &mut [u8]) {
+        assert!(!self.finished);
+        let mut state = self.state;
+        let mut block = [0u8; 64];
+        let used = self.buffer.position();
+        copy_memory(block.slice_to_mut(used), self.buffer.buffer.slice_to(used));
+        block[used] = 128;
+        if used >= 56 {
+            state.process_blocks(self.backend, block.as_slice());
+            block.set_memory(0);
+        }
+        write_u32_be(block.slice_mut(56, 60), (self.length_bits >> 32) as u32);
+        write_u32_be(block.slice_mut(60, 64), self.length_bits as u32);
+        state.process_blocks(self.backend, block.as_slice());
+        state.write_digest(out);
+    }
 }
 
 /// The SHA-256 hash algorithm
@@ -490,6 +619,59 @@ impl Sha256 {
             engine: Engine256::new(&H256)
         }
     }
+
+    /// The digest of the input so far, without allocating. More input may follow.
+    pub fn result_array(&self) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        self.engine.finish_snapshot(out.as_mut_slice());
+        out
+    }
+
+    /// The state of the hash so far, for `import_state` to carry on from later or in another
+    /// process. The encoding is `STATE_VERSION` in one byte, the eight state words and the
+    /// length of the input in bits, all big-endian, then the number of input bytes not yet
+    /// making up a whole block, in one byte, followed by those bytes: 42 to 105 bytes in all.
+    /// Like the digest, it reveals something of the input, and the buffered bytes verbatim.
+    pub fn export_state(&self) -> Vec<u8> {
+        let engine = &self.engine;
+        assert!(!engine.finished);
+        let used = engine.buffer.position();
+        let mut out: Vec<u8> = repeat(0u8).take(STATE_HEADER_SIZE + used).collect();
+        out[0] = STATE_VERSION;
+        engine.state.write_digest(out.slice_mut(1, 33));
+        write_u32_be(out.slice_mut(33, 37), (engine.length_bits >> 32) as u32);
+        write_u32_be(out.slice_mut(37, 41), engine.length_bits as u32);
+        out[41] = used as u8;
+        copy_memory(out.slice_from_mut(STATE_HEADER_SIZE), engine.buffer.buffer.slice_to(used));
+        out
+    }
+
+    /// A hash carrying on from a state written by `export_state`, or `None` if the state is of
+    /// another version or doesn't hang together.
+    pub fn import_state(state: &[u8]) -> Option<Sha256> {
+        if state.len() < STATE_HEADER_SIZE || state[0] != STATE_VERSION {
+            return None;
+        }
+        let mut h = [0u32; 8];
+        read_u32v_be(h.as_mut_slice(), state.slice(1, 33));
+        let mut length = [0u32; 2];
+        read_u32v_be(length.as_mut_slice(), state.slice(33, 41));
+        let length_bits = (length[0] as u64) << 32 | length[1] as u64;
+
+        // The buffer holds exactly the input past the last whole block.
+        let used = state[41] as uint;
+        if state.len() != STATE_HEADER_SIZE + used || length_bits % 8 != 0
+                || length_bits / 8 % 64 != used as u64 {
+            return None;
+        }
+
+        let mut sha = Sha256::new();
+        sha.engine.state = Engine256State::new(&h);
+        sha.engine.length_bits = length_bits;
+        copy_memory(sha.engine.buffer.buffer.slice_to_mut(used), state.slice_from(STATE_HEADER_SIZE));
+        sha.engine.buffer.buffer_idx = used;
+        Some(sha)
+    }
 }
 
 impl Digest for Sha256 {
@@ -498,17 +680,7 @@ impl Digest for Sha256 {
     }
 
     fn result(&mut self, out: &mut [u8]) {
-		let mut self_clone = self.clone();
-        self_clone.engine.finish();
-
-        write_u32_be(out.slice_mut(0, 4), self_clone.engine.state.h0);
-        write_u32_be(out.slice_mut(4, 8), self_clone.engine.state.h1);
-        write_u32_be(out.slice_mut(8, 12), self_clone.engine.state.h2);
-        write_u32_be(out.slice_mut(12, 16), self_clone.engine.state.h3);
-        write_u32_be(out.slice_mut(16, 20), self_clone.engine.state.h4);
-        write_u32_be(out.slice_mut(20, 24), self_clone.engine.state.h5);
-        write_u32_be(out.slice_mut(24, 28), self_clone.engine.state.h6);
-        write_u32_be(out.slice_mut(28, 32), self_clone.engine.state.h7);
+        self.engine.finish_snapshot(out.slice_mut(0, 32));
     }
 
     fn reset(&mut self) {
@@ -518,6 +690,160 @@ impl Digest for Sha256 {
     fn output_bits(&self) -> uint { 256 }
 }
 
+impl Writer for Sha256 {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.input(buf);
+        Ok(())
+    }
+}
+
+/// A reader which hashes everything read through it.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Reader> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader { inner: inner, hasher: Sha256::new() }
+    }
+
+    /// The digest of what has been read so far.
+    pub fn sha256(&self) -> [u8; 32] {
+        self.hasher.result_array()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The reader, and the digest of what was read from it.
+    pub fn into_inner(self) -> (R, [u8; 32]) {
+        let digest = self.sha256();
+        (self.inner, digest)
+    }
+}
+
+impl<R: Reader> Reader for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let n = try!(self.inner.read(buf));
+        self.hasher.input(buf.slice_to(n));
+        Ok(n)
+    }
+}
+
+/// A writer which hashes everything written through it. Only writes which succeed are hashed.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Writer> HashingWriter<W> {
+    pub fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter { inner: inner, hasher: Sha256::new() }
+    }
+
+    /// The digest of what has been written so far.
+    pub fn sha256(&self) -> [u8; 32] {
+        self.hasher.result_array()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The writer, and the digest of what was written to it.
+    pub fn into_inner(self) -> (W, [u8; 32]) {
+        let digest = self.sha256();
+        (self.inner, digest)
+    }
+}
+
+impl<W: Writer> Writer for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        try!(self.inner.write(buf));
+        self.hasher.input(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+/// The version of the encoding `Sha256::export_state` writes, and the only one
+/// `Sha256::import_state` reads.
+pub static STATE_VERSION: u8 = 1;
+
+/// The size of an exported state with nothing buffered
+static STATE_HEADER_SIZE: uint = 42;
+
+static HEX_DIGITS: &'static [u8] = b"0123456789abcdef";
+
+/// Lowercase hex, two digits per byte.
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(2 * bytes.len());
+    for &byte in bytes.iter() {
+        out.push(HEX_DIGITS[(byte >> 4) as uint] as char);
+        out.push(HEX_DIGITS[(byte & 0xf) as uint] as char);
+    }
+    out
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'...b'9' => Some(digit - b'0'),
+        b'a'...b'f' => Some(digit - b'a' + 10),
+        b'A'...b'F' => Some(digit - b'A' + 10),
+        _ => None
+    }
+}
+
+/// Parses a digest written as 64 hex digits, in either case.
+pub fn digest_from_hex(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        match (hex_value(pair[0]), hex_value(pair[1])) {
+            (Some(high), Some(low)) => digest[i] = high << 4 | low,
+            _ => return None
+        }
+    }
+    Some(digest)
+}
+
+/// HMAC-SHA256, as in RFC 2104, of `message` under `key`.  Unlike a plain digest, only someone
+/// holding the key can compute it.
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    // Keys longer than a block are hashed first; shorter ones are padded with zeros.
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        let mut sha = Sha256::new();
+        sha.input(key);
+        copy_memory(block.as_mut_slice(), sha.result_array().as_slice());
+    } else {
+        copy_memory(block.as_mut_slice(), key);
+    }
+
+    let mut pad = [0u8; 64];
+    for (p, &k) in pad.iter_mut().zip(block.iter()) {
+        *p = k ^ 0x36;
+    }
+    let mut inner = Sha256::new();
+    inner.input(pad.as_slice());
+    inner.input(message);
+    for (p, &k) in pad.iter_mut().zip(block.iter()) {
+        *p = k ^ 0x5c;
+    }
+    let mut outer = Sha256::new();
+    outer.input(pad.as_slice());
+    outer.input(inner.result_array().as_slice());
+    outer.result_array()
+}
+
 static H256: [u32; 8] = [
     0x6a09e667,
     0xbb67ae85,
@@ -528,3 +854,215 @@ static H256: [u32; 8] = [
     0x1f83d9ab,
     0x5be0cd19
 ];
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader, EndOfFile};
+    use std::iter::range_step;
+    use super::{Sha256, Digest, Backend, HashingReader, HashingWriter, to_hex, digest_from_hex};
+    use super::hmac_sha256;
+    use super::STATE_VERSION;
+
+    /// The examples from FIPS 180-2 and the empty message, as (message, repetitions, digest).
+    static NIST_VECTORS: [(&'static str, uint, &'static str); 5] = [
+        ("", 1,
+         "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+        ("abc", 1,
+         "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+        ("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq", 1,
+         "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+        ("abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu", 1,
+         "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1"),
+        ("a", 1000000,
+         "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"),
+    ];
+
+    fn with_backend(backend: Backend) -> Sha256 {
+        let mut sha = Sha256::new();
+        sha.engine.backend = backend;
+        sha
+    }
+
+    #[test]
+    fn nist_vectors_all_backends() {
+        for &backend in Backend::available().iter() {
+            for &(message, repeat, expected) in NIST_VECTORS.iter() {
+                let mut whole = String::new();
+                for _ in range(0, repeat) {
+                    whole.push_str(message);
+                }
+                let mut sha = with_backend(backend);
+                sha.input(whole.as_bytes());
+                assert_eq!(to_hex(sha.result_array().as_slice()), expected.to_string());
+
+                // Fed in pieces, so the buffered path is used as well.
+                let mut sha = with_backend(backend);
+                for piece in whole.as_bytes().chunks(97) {
+                    sha.input(piece);
+                }
+                assert_eq!(to_hex(sha.result_array().as_slice()), expected.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn backends_agree() {
+        let data: Vec<u8> = range(0u, 4096).map(|i| (i * 131 + 7) as u8).collect();
+        for len in range(0u, 300).chain(range_step(300u, 4096, 61)) {
+            let mut scalar = with_backend(Backend::Scalar);
+            scalar.input(data.slice_to(len));
+            for &backend in Backend::available().iter() {
+                let mut sha = with_backend(backend);
+                sha.input(data.slice_to(len / 2));
+                sha.input(data.slice(len / 2, len));
+                assert!(sha.result_array() == scalar.result_array(), "{:?} differs at length {}", backend, len);
+            }
+        }
+    }
+
+    #[test]
+    fn snapshots_leave_the_hash_running() {
+        let mut running = Sha256::new();
+        let mut out = [0u8; 32];
+        for i in range(0u, 200) {
+            running.input(&[i as u8]);
+            running.result(out.as_mut_slice());
+            let mut fresh = Sha256::new();
+            for j in range(0u, i + 1) {
+                fresh.input(&[j as u8]);
+            }
+            assert!(fresh.result_array() == out);
+        }
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let (_, _, expected) = NIST_VECTORS[1];
+        let digest = digest_from_hex(expected).unwrap();
+        assert_eq!(to_hex(digest.as_slice()), expected.to_string());
+        let upper: String = expected.chars().map(|c| c.to_uppercase()).collect();
+        assert!(digest_from_hex(upper.as_slice()) == Some(digest));
+        assert!(digest_from_hex(expected.slice_to(62)).is_none());
+        assert!(digest_from_hex(format!("{}00", expected).as_slice()).is_none());
+        assert!(digest_from_hex(format!("{}g", expected.slice_to(63)).as_slice()).is_none());
+        assert_eq!(to_hex(&[0x00, 0x0f, 0xf0, 0xff]), "000ff0ff".to_string());
+    }
+
+    #[test]
+    fn adapters_hash_what_passes_through() {
+        let (message, _, expected) = NIST_VECTORS[3];
+        let expected = digest_from_hex(expected).unwrap();
+
+        let mut sha = Sha256::new();
+        for piece in message.as_bytes().chunks(7) {
+            sha.write(piece).unwrap();
+        }
+        assert!(sha.result_array() == expected);
+
+        let mut reader = HashingReader::new(BufReader::new(message.as_bytes()));
+        let mut buf = [0u8; 10];
+        loop {
+            match reader.read(buf.as_mut_slice()) {
+                Ok(_) => (),
+                Err(ref err) if err.kind == EndOfFile => break,
+                Err(err) => panic!("{}", err)
+            }
+        }
+        assert!(reader.sha256() == expected);
+
+        let mut writer = HashingWriter::new(Vec::new());
+        for piece in message.as_bytes().chunks(13) {
+            writer.write(piece).unwrap();
+        }
+        let (written, digest) = writer.into_inner();
+        assert!(written.as_slice() == message.as_bytes());
+        assert!(digest == expected);
+    }
+
+    #[test]
+    fn state_encoding_is_stable() {
+        let mut sha = Sha256::new();
+        sha.input(b"abc");
+        let mut expected = vec![STATE_VERSION];
+        expected.push_all(&[0x6a, 0x09, 0xe6, 0x67, 0xbb, 0x67, 0xae, 0x85,
+                            0x3c, 0x6e, 0xf3, 0x72, 0xa5, 0x4f, 0xf5, 0x3a,
+                            0x51, 0x0e, 0x52, 0x7f, 0x9b, 0x05, 0x68, 0x8c,
+                            0x1f, 0x83, 0xd9, 0xab, 0x5b, 0xe0, 0xcd, 0x19]);
+        expected.push_all(&[0, 0, 0, 0, 0, 0, 0, 24, 3]);
+        expected.push_all(b"abc");
+        assert!(sha.export_state() == expected);
+    }
+
+    #[test]
+    fn exported_state_resumes_at_every_boundary() {
+        let data: Vec<u8> = range(0u, 300).map(|i| (i * 131 + 7) as u8).collect();
+        let mut whole = Sha256::new();
+        whole.input(data.as_slice());
+        let expected = whole.result_array();
+
+        for split in range(0u, data.len() + 1) {
+            let mut first = Sha256::new();
+            first.input(data.slice_to(split));
+            let state = first.export_state();
+            assert_eq!(state.len(), 42 + split % 64);
+
+            let mut resumed = Sha256::import_state(state.as_slice()).unwrap();
+            assert!(resumed.result_array() == first.result_array());
+            assert!(resumed.export_state() == state);
+            resumed.input(data.slice_from(split));
+            assert!(resumed.result_array() == expected, "differs after export at {}", split);
+        }
+    }
+
+    #[test]
+    fn bad_states_rejected() {
+        let mut sha = Sha256::new();
+        sha.input(b"abc");
+        let state = sha.export_state();
+        assert!(Sha256::import_state(state.as_slice()).is_some());
+
+        let mut other_version = state.clone();
+        other_version[0] = STATE_VERSION + 1;
+        assert!(Sha256::import_state(other_version.as_slice()).is_none());
+
+        assert!(Sha256::import_state(state.slice_to(44)).is_none());
+        assert!(Sha256::import_state(state.slice_to(10)).is_none());
+        let mut longer = state.clone();
+        longer.push(0);
+        assert!(Sha256::import_state(longer.as_slice()).is_none());
+
+        // The length must account for what is buffered, in whole bytes.
+        let mut wrong_length = state.clone();
+        wrong_length[40] = 32;
+        assert!(Sha256::import_state(wrong_length.as_slice()).is_none());
+        wrong_length[40] = 25;
+        assert!(Sha256::import_state(wrong_length.as_slice()).is_none());
+        // A whole block further on is fine.
+        wrong_length[39] = 2;
+        wrong_length[40] = 24;
+        assert!(Sha256::import_state(wrong_length.as_slice()).is_some());
+    }
+
+    /// Test cases 1, 2, 4 and 6 from RFC 4231, as (key, message, HMAC).  Between them they
+    /// cover a key shorter than a block, a message longer than one and a key which is hashed.
+    #[test]
+    fn hmac_rfc4231_vectors() {
+        let short_key = [0x0bu8; 20];
+        let long_key: Vec<u8> = range(0, 131u).map(|_| 0xaau8).collect();
+        let counting_key: Vec<u8> = range(1, 26u).map(|n| n as u8).collect();
+        let long_message: Vec<u8> = range(0, 50u).map(|_| 0xcdu8).collect();
+        let vectors: [(&[u8], &[u8], &str); 4] = [
+            (short_key.as_slice(), b"Hi There",
+             "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+            (b"Jefe", b"what do ya want for nothing?",
+             "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            (counting_key.as_slice(), long_message.as_slice(),
+             "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b"),
+            (long_key.as_slice(), b"Test Using Larger Than Block-Size Key - Hash Key First",
+             "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"),
+        ];
+        for &(key, message, expected) in vectors.iter() {
+            assert_eq!(to_hex(hmac_sha256(key, message).as_slice()), expected);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+/*
+ * Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+/*
+ * SHA-256 compression using the x86 SHA extensions or the ARMv8 crypto
+ * extensions, for src/sha256.rs.  Each function is compiled for its
+ * instruction set alone, so the library still loads on CPUs without it;
+ * reliable_rw_sha256_backend says which may be called.
+ *
+ * The state is the eight words h0..h7 in order, as in Engine256State, and
+ * data is blocks * 64 bytes of message.
+ */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define BACKEND_SCALAR 0
+#define BACKEND_SHANI 1
+#define BACKEND_ARMV8 2
+
+#if defined(__x86_64__) || defined(__i386__) || defined(__aarch64__)
+static const uint32_t K[64] = {
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5,
+    0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc,
+    0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+    0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3,
+    0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5,
+    0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+};
+#endif
+
+
+#if defined(__x86_64__) || defined(__i386__)
+
+#include <cpuid.h>
+#include <immintrin.h>
+
+int reliable_rw_sha256_backend(void)
+{
+    unsigned int eax, ebx, ecx, edx;
+
+    if (__get_cpuid_max(0, 0) < 7)
+        return BACKEND_SCALAR;
+    __cpuid(1, eax, ebx, ecx, edx);
+    /* SSSE3 and SSE4.1, for the byte shuffles and blends */
+    if (!(ecx & (1 << 9)) || !(ecx & (1 << 19)))
+        return BACKEND_SCALAR;
+    __cpuid_count(7, 0, eax, ebx, ecx, edx);
+    if (!(ebx & (1 << 29)))
+        return BACKEND_SCALAR;
+    return BACKEND_SHANI;
+}
+
+__attribute__((target("sha,sse4.1")))
+void reliable_rw_sha256_shani(uint32_t state[8], const uint8_t *data, size_t blocks)
+{
+    /* Byte-swaps each 32-bit word, since the message is big-endian. */
+    const __m128i swap = _mm_set_epi64x(0x0c0d0e0f08090a0bULL, 0x0405060700010203ULL);
+    __m128i abef, cdgh, tmp, msg[4];
+    int i;
+
+    /* The instructions want the state as ABEF and CDGH. */
+    tmp = _mm_shuffle_epi32(_mm_loadu_si128((const __m128i *)&state[0]), 0xb1);
+    cdgh = _mm_shuffle_epi32(_mm_loadu_si128((const __m128i *)&state[4]), 0x1b);
+    abef = _mm_alignr_epi8(tmp, cdgh, 8);
+    cdgh = _mm_blend_epi16(cdgh, tmp, 0xf0);
+
+    while (blocks--) {
+        __m128i abef_start = abef, cdgh_start = cdgh;
+
+        for (i = 0; i < 4; i++)
+            msg[i] = _mm_shuffle_epi8(_mm_loadu_si128((const __m128i *)(data + 16 * i)), swap);
+
+        /* msg[i & 3] holds words 4i..4i+3 of the schedule in turn. */
+        for (i = 0; i < 16; i++) {
+            __m128i wk;
+            if (i >= 4) {
+                tmp = _mm_sha256msg1_epu32(msg[i & 3], msg[(i + 1) & 3]);
+                tmp = _mm_add_epi32(tmp, _mm_alignr_epi8(msg[(i + 3) & 3], msg[(i + 2) & 3], 4));
+                msg[i & 3] = _mm_sha256msg2_epu32(tmp, msg[(i + 3) & 3]);
+            }
+            wk = _mm_add_epi32(msg[i & 3], _mm_loadu_si128((const __m128i *)&K[4 * i]));
+            cdgh = _mm_sha256rnds2_epu32(cdgh, abef, wk);
+            abef = _mm_sha256rnds2_epu32(abef, cdgh, _mm_shuffle_epi32(wk, 0x0e));
+        }
+
+        abef = _mm_add_epi32(abef, abef_start);
+        cdgh = _mm_add_epi32(cdgh, cdgh_start);
+        data += 64;
+    }
+
+    tmp = _mm_shuffle_epi32(abef, 0x1b);
+    cdgh = _mm_shuffle_epi32(cdgh, 0xb1);
+    _mm_storeu_si128((__m128i *)&state[0], _mm_blend_epi16(tmp, cdgh, 0xf0));
+    _mm_storeu_si128((__m128i *)&state[4], _mm_alignr_epi8(cdgh, tmp, 8));
+}
+
+#elif defined(__aarch64__)
+
+#include <arm_neon.h>
+#if defined(__linux__)
+#include <sys/auxv.h>
+#include <asm/hwcap.h>
+#endif
+
+int reliable_rw_sha256_backend(void)
+{
+#if defined(__linux__) && defined(HWCAP_SHA2)
+    if (getauxval(AT_HWCAP) & HWCAP_SHA2)
+        return BACKEND_ARMV8;
+#endif
+    return BACKEND_SCALAR;
+}
+
+__attribute__((target("arch=armv8-a+crypto")))
+void reliable_rw_sha256_armv8(uint32_t state[8], const uint8_t *data, size_t blocks)
+{
+    uint32x4_t abcd = vld1q_u32(&state[0]);
+    uint32x4_t efgh = vld1q_u32(&state[4]);
+    uint32x4_t msg[4];
+    int i;
+
+    while (blocks--) {
+        uint32x4_t abcd_start = abcd, efgh_start = efgh;
+
+        for (i = 0; i < 4; i++)
+            msg[i] = vreinterpretq_u32_u8(vrev32q_u8(vld1q_u8(data + 16 * i)));
+
+        /* msg[i & 3] holds words 4i..4i+3 of the schedule in turn. */
+        for (i = 0; i < 16; i++) {
+            uint32x4_t wk, abcd_before;
+            if (i >= 4)
+                msg[i & 3] = vsha256su1q_u32(vsha256su0q_u32(msg[i & 3], msg[(i + 1) & 3]),
+                                             msg[(i + 2) & 3], msg[(i + 3) & 3]);
+            wk = vaddq_u32(msg[i & 3], vld1q_u32(&K[4 * i]));
+            abcd_before = abcd;
+            abcd = vsha256hq_u32(abcd, efgh, wk);
+            efgh = vsha256h2q_u32(efgh, abcd_before, wk);
+        }
+
+        abcd = vaddq_u32(abcd, abcd_start);
+        efgh = vaddq_u32(efgh, efgh_start);
+        data += 64;
+    }
+
+    vst1q_u32(&state[0], abcd);
+    vst1q_u32(&state[4], efgh);
+}
+
+#else
+
+int reliable_rw_sha256_backend(void)
+{
+    return BACKEND_SCALAR;
+}
+
+#endif
@@ -0,0 +1,106 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Read timeouts for pipes and sockets, which `std::io` only offers for
+//! some of its own types.
+
+use libc::c_int;
+use std::io::{IoResult, IoError, TimedOut};
+
+use posix;
+
+
+/// Wraps a reader on the file descriptor `fd`, failing reads with
+/// `TimedOut` rather than blocking for too long.  `fd` must be the
+/// descriptor `inner` reads from, and `inner` must not buffer, or data
+/// it already holds would go unnoticed while we wait on `fd`.
+pub struct TimeoutReader<R> {
+    inner: R,
+    fd: c_int,
+    /// The longest we may go without input, in milliseconds
+    idle_ms: Option<u64>,
+    /// When reads stop being allowed at all, in `posix::monotonic_ms` time
+    deadline: Option<u64>,
+    /// When input last arrived, in `posix::monotonic_ms` time
+    last_input: u64,
+}
+
+
+fn ms_until(at: u64, now: u64) -> u64 {
+    if now < at { at - now } else { 0 }
+}
+
+
+impl<R: Reader> TimeoutReader<R> {
+    /// Both timeouts are counted from now: `deadline_ms` is the total
+    /// time allowed for all reads from this one on.
+    pub fn new(inner: R, fd: c_int, idle_ms: Option<u64>, deadline_ms: Option<u64>) -> TimeoutReader<R> {
+        let now = posix::monotonic_ms();
+        TimeoutReader {
+            inner: inner,
+            fd: fd,
+            idle_ms: idle_ms,
+            deadline: deadline_ms.map(|ms| now + ms),
+            last_input: now,
+        }
+    }
+
+    /// Milliseconds until the deadline, or `None` if there is none.
+    pub fn remaining_ms(&self) -> Option<u64> {
+        self.deadline.map(|deadline| ms_until(deadline, posix::monotonic_ms()))
+    }
+
+    /// Milliseconds until the first timeout runs out, and which it is.
+    fn time_left(&self) -> Option<(u64, &'static str)> {
+        let now = posix::monotonic_ms();
+        let idle = self.idle_ms.map(|idle| {
+            (ms_until(self.last_input + idle, now), "no input within idle timeout")
+        });
+        let deadline = self.deadline.map(|deadline| (ms_until(deadline, now), "deadline passed"));
+        match (idle, deadline) {
+            (Some((idle_left, _)), Some((left, desc))) if left < idle_left =>
+                Some((left, desc)),
+            (Some(idle), Some(_)) => Some(idle),
+            (idle, None) => idle,
+            (None, deadline) => deadline,
+        }
+    }
+
+    /// Waits up to `max_ms` (or indefinitely) for input to arrive, and
+    /// returns whether it did.  It is an error for a timeout to run out
+    /// first.  Waiting doesn't count as input, so it doesn't put off the
+    /// idle timeout.
+    pub fn wait(&mut self, max_ms: Option<u64>) -> IoResult<bool> {
+        match (self.time_left(), max_ms) {
+            (None, None) => Ok(true),
+            (None, Some(max)) => posix::wait_readable(self.fd, max),
+            (Some((left, _)), Some(max)) if max < left => posix::wait_readable(self.fd, max),
+            (Some((left, desc)), _) => {
+                if try!(posix::wait_readable(self.fd, left)) {
+                    Ok(true)
+                } else {
+                    Err(IoError { kind: TimedOut, desc: desc, detail: None })
+                }
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+
+impl<R: Reader> Reader for TimeoutReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        try!(self.wait(None));
+        let n = try!(self.inner.read(buf));
+        self.last_input = posix::monotonic_ms();
+        Ok(n)
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Carrying a stream to `reliable-write --receipt` and its commit receipt
+//! back, through a command such as `ssh`.
+
+use libc;
+use std::fmt;
+use std::io::{Command, IoResult, BufferedWriter, PipeStream};
+use std::io::process::{Process, InheritFd, ProcessExit, ExitStatus};
+
+use exit_code;
+use sha256::to_hex;
+use {CommitReceipt, ReceiptKey, ReliableWriteError};
+
+
+/// Quotes `arg` for a POSIX shell, so it reaches the command as one
+/// argument whatever it contains.
+pub fn shell_quote(arg: &[u8]) -> Vec<u8> {
+    let mut quoted = Vec::with_capacity(arg.len() + 2);
+    quoted.push(b'\'');
+    for &byte in arg.iter() {
+        if byte == b'\'' {
+            quoted.push_all(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+
+/// Splits an scp-style destination, `[user@]host:path`, into its host, if
+/// it has one, and path.  As with scp, a colon after a slash is part of the
+/// path.  Returns `None` if the host or path would be taken for an option,
+/// by the transport or by `reliable-write`.
+pub fn split_dest(dest: &str) -> Option<(Option<String>, String)> {
+    let (host, path) = match dest.find(':') {
+        Some(colon) if colon > 0 && !dest.slice_to(colon).contains("/") =>
+            (Some(dest.slice_to(colon).to_string()), dest.slice_from(colon + 1).to_string()),
+        _ => (None, dest.to_string())
+    };
+    match host {
+        Some(ref host) if host.starts_with("-") => return None,
+        _ => ()
+    }
+    if path.len() == 0 || path.starts_with("-") {
+        return None;
+    }
+    Some((host, path))
+}
+
+
+/// What the receiver made of a stream.
+pub enum Delivery {
+    /// The payload we sent was committed
+    Committed(CommitReceipt),
+    /// No receipt we could read came back.  Carries how the transport
+    /// exited, if we know.
+    NoReceipt(ReliableWriteError, Option<ProcessExit>),
+    /// The receiver failed, and nothing was committed unless its status
+    /// says only a post-commit hook failed
+    Rejected(CommitReceipt),
+    /// The receiver committed a payload other than the one we sent
+    Mismatch(CommitReceipt),
+    /// The receipt wasn't signed with our key for this stream, so it may
+    /// not be from the receiver at all
+    Unverified(CommitReceipt),
+}
+
+
+impl Delivery {
+    /// The exit status which reports this, from the table in `exit_code`.
+    /// A rejection is reported with the receiver's own status.
+    pub fn exit_code(&self) -> int {
+        match *self {
+            Delivery::Committed(_) => exit_code::SUCCESS,
+            Delivery::NoReceipt(..) => exit_code::NO_RECEIPT,
+            Delivery::Rejected(ref receipt) => receipt.status as int,
+            Delivery::Mismatch(_) => exit_code::INTEGRITY,
+            Delivery::Unverified(_) => exit_code::NO_RECEIPT,
+        }
+    }
+}
+
+
+impl fmt::String for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Delivery::Committed(ref receipt) =>
+                write!(f, "committed {}", String::from_utf8_lossy(receipt.path.as_slice())),
+            Delivery::NoReceipt(ref err, Some(ExitStatus(0))) | Delivery::NoReceipt(ref err, None) =>
+                write!(f, "no commit receipt from receiver: {}", err),
+            Delivery::NoReceipt(ref err, Some(exit)) =>
+                write!(f, "no commit receipt from receiver: {} (transport {})", err, exit),
+            Delivery::Rejected(ref receipt) =>
+                write!(f, "receiver did not commit {}: status {}",
+                       String::from_utf8_lossy(receipt.path.as_slice()), receipt.status),
+            Delivery::Mismatch(ref receipt) =>
+                write!(f, "receiver committed a different payload to {}: {} bytes, sha256 {}",
+                       String::from_utf8_lossy(receipt.path.as_slice()), receipt.size,
+                       to_hex(receipt.sha256.as_slice())),
+            Delivery::Unverified(ref receipt) if receipt.mac.is_none() =>
+                write!(f, "commit receipt from receiver is not signed"),
+            Delivery::Unverified(_) =>
+                write!(f, "commit receipt from receiver is not signed with our key for this stream"),
+        }
+    }
+}
+
+
+/// Why a stream sent through a transport wasn't committed.
+pub enum SendFailure<F> {
+    /// Sending the stream failed, for the caller's reason
+    Send(F),
+    /// The receiver didn't confirm it committed what we sent
+    Delivery(Delivery),
+}
+
+
+/// A running transport command.  Write the stream to `input`, then
+/// `finish` to collect the receipt.
+pub struct Transport {
+    process: Process,
+    /// Set by `require_signed`
+    verify: Option<(ReceiptKey, [u8; 16])>,
+}
+
+
+impl Transport {
+    /// Runs `sh -c cmd`.  Its stderr is ours, so the receiver's
+    /// diagnostics show up alongside our own.
+    pub fn shell(cmd: &[u8]) -> IoResult<Transport> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command.stderr(InheritFd(libc::STDERR_FILENO));
+        Ok(Transport { process: try!(command.spawn()), verify: None })
+    }
+
+    /// Only accept a receipt signed with `key` for the stream whose header
+    /// carries `nonce`.  Anything else is `Delivery::Unverified`, whatever
+    /// it says.
+    pub fn require_signed(&mut self, key: ReceiptKey, nonce: [u8; 16]) {
+        self.verify = Some((key, nonce));
+    }
+
+    /// Where to write the stream.  Dropping it closes the transport's
+    /// stdin, which ends the stream.  May only be called once.
+    pub fn input(&mut self) -> BufferedWriter<PipeStream> {
+        BufferedWriter::new(self.process.stdin.take().expect("transport input already taken"))
+    }
+
+    /// Reads the receipt and waits for the transport to exit.  `sent` is
+    /// the SHA-256 and size of the payload, or `None` if we couldn't send
+    /// all of it.
+    pub fn finish(mut self, sent: Option<([u8; 32], u64)>) -> Delivery {
+        drop(self.process.stdin.take());
+        let receipt = CommitReceipt::read_from(self.process.stdout.as_mut().unwrap());
+        let exit = self.process.wait().ok();
+        let receipt = match receipt {
+            Ok(receipt) => receipt,
+            Err(err) => return Delivery::NoReceipt(err, exit)
+        };
+        match self.verify {
+            Some((ref key, ref nonce)) if !receipt.verify(key, nonce) =>
+                return Delivery::Unverified(receipt),
+            _ => ()
+        }
+        if receipt.status != exit_code::SUCCESS as u32 {
+            return Delivery::Rejected(receipt);
+        }
+        match sent {
+            Some((ref digest, size)) if receipt.size == size &&
+                                        receipt.sha256.as_slice() == digest.as_slice() =>
+                Delivery::Committed(receipt),
+            _ => Delivery::Mismatch(receipt)
+        }
+    }
+
+    /// Like `finish`, but given how sending went, and says which failure
+    /// to report.  `write_failed` picks out failures to write the stream:
+    /// the receiver giving up is the likeliest reason for those, and it
+    /// knows why.
+    pub fn conclude<F, P>(self, sent: Result<([u8; 32], u64), F>, write_failed: P) -> Result<(), SendFailure<F>>
+        where P: Fn(&F) -> bool
+    {
+        let delivery = self.finish(sent.as_ref().ok().map(|sent| *sent));
+        let failure = match sent {
+            Ok(_) => None,
+            Err(failure) => Some(failure)
+        };
+        match (delivery, failure) {
+            (Delivery::Committed(_), None) => Ok(()),
+            (delivery, None) => Err(SendFailure::Delivery(delivery)),
+            (delivery, Some(failure)) => {
+                let rejected = match delivery {
+                    Delivery::Rejected(_) => true,
+                    _ => false
+                };
+                if rejected && write_failed(&failure) {
+                    Err(SendFailure::Delivery(delivery))
+                } else {
+                    Err(SendFailure::Send(failure))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checks the wire format against streams built independently of the
+//! crate's encoder by `golden/generate.py`, from the format as documented
+//! in `reliable_rw`.  They pin the format down, but were never produced by
+//! or checked against the Python implementation, so they say nothing about
+//! compatibility with it.  If one of these fails, the stream format has
+//! changed; regenerate the files only if that was the intent.
+
+extern crate reliable_rw;
+
+use std::io::BufReader;
+use reliable_rw::{copy_out, ReliableEncap, ReliableDecap, ReliableWriteError, PIECE_SIZE};
+
+
+static EMPTY: &'static [u8] = include_bytes!("golden/empty.bin");
+static ONE_BYTE: &'static [u8] = include_bytes!("golden/one-byte.bin");
+static ONE_PIECE: &'static [u8] = include_bytes!("golden/one-piece.bin");
+static MULTI_PIECE: &'static [u8] = include_bytes!("golden/multi-piece.bin");
+static PRODUCER_FAILED: &'static [u8] = include_bytes!("golden/producer-failed.bin");
+static HEARTBEAT: &'static [u8] = include_bytes!("golden/heartbeat.bin");
+
+
+/// The payload of `length` bytes used by `generate.py`.
+fn payload(length: uint) -> Vec<u8> {
+    range(0, length).map(|i| (i % 251) as u8).collect()
+}
+
+
+/// Encodes `data`, read in chunks of `chunk` bytes.  A stream which isn't
+/// `complete` ends as it does when the producer fails.
+fn encode(data: &[u8], chunk: uint, complete: bool) -> Vec<u8> {
+    let mut stream = Vec::new();
+    {
+        let mut encapper = ReliableEncap::new(&mut stream).unwrap();
+        for piece in data.chunks(chunk) {
+            encapper.update(&piece.to_vec()).unwrap();
+        }
+        encapper.finish_write().unwrap();
+        if complete {
+            encapper.finalize().unwrap();
+        }
+    }
+    stream
+}
+
+
+fn decode(stream: &[u8]) -> Result<Vec<u8>, ReliableWriteError> {
+    let mut output = Vec::new();
+    try!(copy_out(&mut BufReader::new(stream), &mut output));
+    Ok(output)
+}
+
+
+fn check_golden(golden: &[u8], length: uint) {
+    let data = payload(length);
+    assert!(encode(data.as_slice(), PIECE_SIZE, true).as_slice() == golden);
+    match decode(golden) {
+        Ok(output) => assert!(output == data),
+        Err(err) => panic!("golden stream rejected: {}", err)
+    }
+}
+
+
+#[test]
+fn empty() {
+    check_golden(EMPTY, 0);
+}
+
+
+#[test]
+fn one_byte() {
+    check_golden(ONE_BYTE, 1);
+}
+
+
+#[test]
+fn one_piece() {
+    check_golden(ONE_PIECE, PIECE_SIZE);
+}
+
+
+#[test]
+fn multi_piece() {
+    check_golden(MULTI_PIECE, 2 * PIECE_SIZE + 100);
+}
+
+
+#[test]
+fn piece_boundaries_follow_piece_size() {
+    // `update` splits a larger buffer into pieces of PIECE_SIZE, so one
+    // big read encodes the same as several reads of PIECE_SIZE.
+    let data = payload(2 * PIECE_SIZE + 100);
+    assert!(encode(data.as_slice(), 2 * PIECE_SIZE + 100, true).as_slice() == MULTI_PIECE);
+}
+
+
+#[test]
+fn producer_failed() {
+    let data = payload(1000);
+    assert!(encode(data.as_slice(), PIECE_SIZE, false).as_slice() == PRODUCER_FAILED);
+    match decode(PRODUCER_FAILED) {
+        Err(ReliableWriteError::ProducerError(None)) => (),
+        Ok(_) => panic!("failed producer's stream accepted"),
+        Err(err) => panic!("wrong error: {}", err)
+    }
+}
+
+
+#[test]
+fn heartbeats() {
+    let data = payload(PIECE_SIZE + 100);
+    let mut stream = Vec::new();
+    {
+        let mut encapper = ReliableEncap::new(&mut stream).unwrap();
+        for piece in data.chunks(PIECE_SIZE) {
+            encapper.heartbeat(1500).unwrap();
+            encapper.update(&piece.to_vec()).unwrap();
+        }
+        encapper.finish_write().unwrap();
+        encapper.finalize().unwrap();
+    }
+    assert!(stream.as_slice() == HEARTBEAT);
+
+    let mut input = BufReader::new(HEARTBEAT);
+    let mut decap = ReliableDecap::new(&mut input).unwrap();
+    let mut output = Vec::new();
+    decap.copy_to(&mut output).unwrap();
+    assert!(output == data);
+    assert_eq!(decap.heartbeat_interval(), Some(1500));
+}
+
+
+#[test]
+fn truncated_streams_rejected() {
+    // Cutting off the final digest is left out: that is how a failed
+    // producer is reported.
+    for &len in [0, 10, 14, 18, 100, PIECE_SIZE + 18, MULTI_PIECE.len() - 33].iter() {
+        match decode(MULTI_PIECE.slice_to(len)) {
+            Err(ReliableWriteError::TruncatedError) => (),
+            Ok(_) => panic!("stream truncated to {} bytes accepted", len),
+            Err(err) => panic!("stream truncated to {} bytes: wrong error: {}", len, err)
+        }
+    }
+}
+
+
+#[test]
+fn damaged_streams_rejected() {
+    // A byte of the first piece's data, of its digest, and of the final
+    // digest.
+    for &offset in [100, 18 + PIECE_SIZE, MULTI_PIECE.len() - 1].iter() {
+        let mut stream = MULTI_PIECE.to_vec();
+        stream[offset] ^= 0x01;
+        match decode(stream.as_slice()) {
+            Err(ReliableWriteError::IntegrityError) => (),
+            Ok(_) => panic!("stream damaged at {} accepted", offset),
+            Err(err) => panic!("stream damaged at {}: wrong error: {}", offset, err)
+        }
+    }
+}
+
+
+#[test]
+fn fill_from_encodes_reads_as_pieces() {
+    // BufReader hands back as much as asked for, so each read is a full
+    // piece, as `update` would have made.
+    let data = payload(2 * PIECE_SIZE + 100);
+    let mut input = BufReader::new(data.as_slice());
+    let mut stream = Vec::new();
+    {
+        let mut encapper = ReliableEncap::new(&mut stream).unwrap();
+        while encapper.fill_from(&mut input).is_ok() {
+            encapper.send_filled().unwrap();
+        }
+        encapper.finish_write().unwrap();
+        encapper.finalize().unwrap();
+    }
+    assert!(stream.as_slice() == MULTI_PIECE);
+}
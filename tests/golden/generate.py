@@ -0,0 +1,57 @@
+#!/usr/bin/env python
+# Writes the golden streams in this directory.  This builds them from the
+# wire format as documented in the crate, with hashlib, rather than with
+# the crate's encoder, so the tests in ../golden.rs compare the two.  It
+# is not the Python implementation, and its output hasn't been checked
+# against that.  The payloads must match `payload` in ../golden.rs.
+
+import hashlib
+import os
+import struct
+
+MAGIC_HEADER = b'reliable-encap'
+PIECE_SIZE = 32 * 1024
+RECORD_FLAG = 0x80000000
+RECORD_HEARTBEAT = 5
+
+
+def payload(length):
+    return bytes(bytearray(i % 251 for i in range(length)))
+
+
+def record(kind, body):
+    header = struct.pack('>II', RECORD_FLAG | kind, len(body))
+    return header + body + hashlib.sha256(header + body).digest()
+
+
+def encode(data, complete=True, heartbeat=None):
+    hasher = hashlib.sha256()
+    out = [MAGIC_HEADER]
+    for start in range(0, len(data), PIECE_SIZE):
+        # A heartbeat, if any, before every piece
+        if heartbeat is not None:
+            out.append(record(RECORD_HEARTBEAT, struct.pack('>I', heartbeat)))
+        piece = data[start:start + PIECE_SIZE]
+        hasher.update(piece)
+        out += [struct.pack('>I', len(piece)), piece, hasher.digest()]
+    out += [struct.pack('>I', 0), hasher.digest()]
+    # A failed producer's stream stops short of the final digest.
+    if complete:
+        out.append(hasher.digest())
+    return b''.join(out)
+
+
+GOLDEN = {
+    'empty.bin': encode(payload(0)),
+    'one-byte.bin': encode(payload(1)),
+    'one-piece.bin': encode(payload(PIECE_SIZE)),
+    'multi-piece.bin': encode(payload(2 * PIECE_SIZE + 100)),
+    'producer-failed.bin': encode(payload(1000), complete=False),
+    'heartbeat.bin': encode(payload(PIECE_SIZE + 100), heartbeat=1500),
+}
+
+if __name__ == '__main__':
+    here = os.path.dirname(os.path.abspath(__file__))
+    for name, stream in sorted(GOLDEN.items()):
+        with open(os.path.join(here, name), 'wb') as f:
+            f.write(stream)
@@ -0,0 +1,125 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A fixed-seed run of the structure-aware mutator, so the decoder's
+//! invariants are checked on every `cargo test`.  Set
+//! `RELIABLE_RW_MUTATION_ROUNDS` for a longer run, and
+//! `RELIABLE_RW_MUTATION_SEED` to explore other mutations.
+
+extern crate reliable_rw;
+
+use std::os;
+use std::io::BufReader;
+use reliable_rw::{copy_out, DecapOptions, EncapOptions, ReliableWriteError, MAX_RECORD_SIZE};
+use mutator::{corpus, frames, mutate, check_decode, check_steered, MeasuredReader, XorShift};
+
+mod mutator;
+
+
+#[test]
+fn corpus_is_valid() {
+    let corpus = corpus();
+    for &(ref payload, ref stream) in corpus.iter() {
+        let mut joined = Vec::new();
+        for frame in frames(stream.as_slice()).iter() {
+            joined.push_all(frame.as_slice());
+        }
+        assert!(joined == *stream);
+        check_decode(stream.as_slice(), payload.as_slice());
+    }
+    // All but the last, whose producer failed, must be accepted.
+    for &(ref payload, ref stream) in corpus.init().iter() {
+        let mut output = Vec::new();
+        assert!(copy_out(&mut BufReader::new(stream.as_slice()), &mut output).is_ok());
+        assert!(output == *payload);
+    }
+}
+
+
+fn env_number(name: &str) -> Option<u64> {
+    os::getenv(name).and_then(|value| value.parse::<u64>())
+}
+
+
+#[test]
+fn mutated_streams_keep_invariants() {
+    let corpus = corpus();
+    let seed = env_number("RELIABLE_RW_MUTATION_SEED").unwrap_or(0x9e37_79b9_7f4a_7c15);
+    let rounds = env_number("RELIABLE_RW_MUTATION_ROUNDS").unwrap_or(20000);
+    // xorshift never leaves zero.
+    let mut choices = XorShift(if seed == 0 { 1 } else { seed });
+    for round in range(0u, rounds as uint) {
+        let (ref payload, ref stream) = corpus[round % corpus.len()];
+        let (_, ref donor) = corpus[round / corpus.len() % corpus.len()];
+        let mutated = mutate(stream.as_slice(), donor.as_slice(), &mut choices);
+        check_decode(mutated.as_slice(), payload.as_slice());
+    }
+}
+
+
+/// Inputs AFL++ found crashing the `mutate` target, built without the
+/// check for data after the final digest, minimised with `afl-tmin`.  Both
+/// splice the end of the empty stream, a zero-length piece and the empty
+/// payload's final digest, into the one-byte stream, which was then
+/// accepted as the empty payload whatever followed.
+static FUZZ_CRASHES: &'static [&'static [u8]] = &[
+    b"\x65\x6e\x49\x61\x93\x49\x61\x93\x0b",
+    b"\x33\x0f\x43\x43\x43\x30\x43\x43\x43\xab\xab\x20\x40",
+];
+
+
+/// An input built by hand to steer `mutate` into the same bug, splicing
+/// the end of the empty stream in right after the magic header.
+static SPLICED_STREAM: &'static [u8] = b"\x1f\xeb\xeb\x30\xeb\x30\x0a\xeb\xeb\x1a\x30\x10\xeb\x1a";
+
+
+#[test]
+fn fuzz_crashes_stay_fixed() {
+    for input in FUZZ_CRASHES.iter() {
+        check_steered(*input);
+    }
+    check_steered(SPLICED_STREAM);
+}
+
+
+#[test]
+fn data_after_final_digest_rejected() {
+    let corpus = corpus();
+    let (_, ref stream) = corpus[1];
+    for extra in [b"\x00", b"reliable-encap"].iter() {
+        let mut damaged = stream.clone();
+        damaged.push_all(*extra);
+        let mut output = Vec::new();
+        match copy_out(&mut BufReader::new(damaged.as_slice()), &mut output) {
+            Err(ReliableWriteError::ProtocolError) => (),
+            other => panic!("accepted with data after it: {:?}", other)
+        }
+    }
+}
+
+
+#[test]
+fn large_pieces_read_in_chunks() {
+    // Raising the piece size mustn't raise how much the decoder holds.
+    let payload: Vec<u8> = range(0u, 3 << 20).map(|i| (i >> 8) as u8).collect();
+    let mut stream = Vec::new();
+    {
+        let mut encapper = EncapOptions::new().piece_size(1 << 20).encap(&mut stream).unwrap();
+        encapper.update(&payload).unwrap();
+        encapper.finish_write().unwrap();
+        encapper.finalize().unwrap();
+    }
+    let mut input = MeasuredReader { inner: BufReader::new(stream.as_slice()), largest_read: 0 };
+    let mut output = Vec::new();
+    {
+        let mut decap = DecapOptions::new().max_piece_size(1 << 20).decap(&mut input).unwrap();
+        assert!(decap.copy_to(&mut output).is_ok());
+    }
+    assert!(output == payload);
+    assert!(input.largest_read <= MAX_RECORD_SIZE);
+}
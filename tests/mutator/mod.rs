@@ -0,0 +1,297 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Structure-aware mutation of encoded streams, and the invariants the
+//! decoder must keep whatever it is fed.  Shared by `tests/mutation.rs`
+//! and the `mutate` fuzz target.
+
+use std::default::Default;
+use std::io::{BufReader, IoResult};
+use std::io::process::ExitStatus;
+use reliable_rw::{copy_out, sha256_of, DecapOptions, EncapOptions, StreamHeader, FileMetadata};
+use reliable_rw::{ProducerStatus, MAGIC_HEADER, MAX_PIECE_SIZE, MAX_RECORD_SIZE, RECORD_FLAG};
+
+
+/// Where a mutation gets its decisions from.
+pub trait Choices {
+    /// A number less than `n`, which is at least 1.
+    fn below(&mut self, n: uint) -> uint;
+}
+
+
+/// xorshift64, for repeatable runs without pulling in a crate.
+pub struct XorShift(pub u64);
+
+
+impl Choices for XorShift {
+    fn below(&mut self, n: uint) -> uint {
+        let XorShift(mut x) = *self;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        *self = XorShift(x);
+        (x >> 32) as uint % n
+    }
+}
+
+
+/// Takes decisions from fuzzer input, a byte at a time, then all zeros
+/// once it runs out.
+pub struct ByteChoices<'a> {
+    pub bytes: &'a [u8],
+}
+
+
+impl<'a> Choices for ByteChoices<'a> {
+    fn below(&mut self, n: uint) -> uint {
+        let mut value = 0u;
+        let mut range = 1u;
+        while range < n && self.bytes.len() > 0 {
+            value = value << 8 | self.bytes[0] as uint;
+            range <<= 8;
+            self.bytes = self.bytes.slice_from(1);
+        }
+        value % n
+    }
+}
+
+
+fn payload(length: uint, seed: uint) -> Vec<u8> {
+    range(0, length).map(|i| (i * 31 + seed) as u8).collect()
+}
+
+
+fn encode(payload: &[u8], options: &EncapOptions, heartbeats: bool, fail: bool) -> IoResult<Vec<u8>> {
+    let mut stream = Vec::new();
+    {
+        let mut encapper = try!(options.encap(&mut stream));
+        for (i, chunk) in payload.chunks(50).enumerate() {
+            if heartbeats && i % 3 == 0 {
+                try!(encapper.heartbeat(1000));
+            }
+            try!(encapper.update(&chunk.to_vec()));
+        }
+        if fail {
+            try!(encapper.abort(&ProducerStatus {
+                exit: ExitStatus(1),
+                usage: Default::default(),
+                stderr_tail: b"pg_dump: error: connection lost\n".to_vec(),
+            }));
+        } else {
+            try!(encapper.finish_write());
+            try!(encapper.finalize());
+        }
+    }
+    Ok(stream)
+}
+
+
+/// Valid streams to start from, as (payload, stream) pairs.  They use a
+/// small piece size so there are plenty of frames to mutate, and between
+/// them they have every kind of record.
+pub fn corpus() -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut plain = EncapOptions::new();
+    plain.piece_size(16);
+
+    let mut described = EncapOptions::new();
+    described.piece_size(16).header(StreamHeader {
+        declared_size: Some(200),
+        metadata: Some(FileMetadata { mode: 0o644, mtime: 1420070400000 }),
+        holes: vec![(32, 64), (128, 16)],
+        piece_size: None,
+        receipt_nonce: Some([0x5au8; 16]),
+    });
+
+    let mut corpus = Vec::new();
+    for &(length, ref options, heartbeats, fail) in [
+        (0, &plain, false, false),
+        (1, &plain, false, false),
+        (100, &plain, false, false),
+        (200, &described, true, false),
+        (100, &plain, true, true),
+    ].iter() {
+        let data = payload(length, corpus.len());
+        let stream = encode(data.as_slice(), *options, heartbeats, fail).unwrap();
+        corpus.push((data, stream));
+    }
+    corpus
+}
+
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+
+fn write_u32(bytes: &mut [u8], value: u32) {
+    bytes[0] = (value >> 24) as u8;
+    bytes[1] = (value >> 16) as u8;
+    bytes[2] = (value >> 8) as u8;
+    bytes[3] = value as u8;
+}
+
+
+/// Splits a valid stream into its magic header, then each record, piece
+/// and terminator with its digest, then the final digest if present.
+pub fn frames(stream: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = vec![stream.slice_to(MAGIC_HEADER.len()).to_vec()];
+    let mut rest = stream.slice_from(MAGIC_HEADER.len());
+    loop {
+        let word = read_u32(rest);
+        let len = if word & RECORD_FLAG != 0 {
+            8 + read_u32(rest.slice_from(4)) as uint + 32
+        } else {
+            4 + word as uint + 32
+        };
+        frames.push(rest.slice_to(len).to_vec());
+        rest = rest.slice_from(len);
+        if word == 0 {
+            break;
+        }
+    }
+    if rest.len() > 0 {
+        frames.push(rest.to_vec());
+    }
+    frames
+}
+
+
+/// Length words worth trying: around the limits the decoder enforces, and
+/// record words of every kind.
+fn interesting_word<C: Choices>(choices: &mut C, current: u32) -> u32 {
+    let max = MAX_PIECE_SIZE as u32;
+    let less = if current > 0 { current - 1 } else { 0 };
+    let more = if current < 0xffff_ffff { current + 1 } else { current };
+    let words = [0, 1, less, more, 16, 17, max, max + 1,
+                 RECORD_FLAG - 1, RECORD_FLAG, 0xffff_ffff];
+    let pick = choices.below(words.len() + 1);
+    if pick < words.len() {
+        words[pick]
+    } else {
+        RECORD_FLAG | choices.below(9) as u32
+    }
+}
+
+
+/// Applies one to four mutations to `stream`, mostly to whole frames, which
+/// must be valid.  Frames from `donor` may be spliced in.
+pub fn mutate<C: Choices>(stream: &[u8], donor: &[u8], choices: &mut C) -> Vec<u8> {
+    let mut parts = frames(stream);
+    let donor = frames(donor);
+    let mut bytes_mutations = Vec::new();
+
+    for _ in range(0, 1 + choices.below(4)) {
+        let i = 1 + choices.below(parts.len() - 1);
+        match choices.below(8) {
+            0 if parts.len() > 2 => { parts.remove(i); },
+            1 => {
+                let frame = parts[i].clone();
+                parts.insert(i, frame);
+            },
+            2 if i + 1 < parts.len() => parts.swap(i, i + 1),
+            3 => {
+                let frame = donor[1 + choices.below(donor.len() - 1)].clone();
+                parts.insert(i, frame);
+            },
+            4 if parts[i].len() >= 4 => {
+                let word = read_u32(parts[i].as_slice());
+                let word = interesting_word(choices, word);
+                write_u32(parts[i].as_mut_slice(), word);
+            },
+            5 if parts[i].len() >= 8 && read_u32(parts[i].as_slice()) & RECORD_FLAG != 0 => {
+                let len = read_u32(parts[i].slice_from(4));
+                let len = interesting_word(choices, len);
+                write_u32(parts[i].slice_from_mut(4), len);
+            },
+            6 => bytes_mutations.push(0),
+            _ => bytes_mutations.push(1),
+        }
+    }
+
+    let mut mutated = Vec::new();
+    for frame in parts.iter() {
+        mutated.push_all(frame.as_slice());
+    }
+    for &kind in bytes_mutations.iter() {
+        if mutated.len() == 0 {
+            break;
+        }
+        let at = choices.below(mutated.len());
+        if kind == 0 {
+            mutated.truncate(at);
+        } else {
+            mutated[at] ^= 1 << choices.below(8);
+        }
+    }
+    mutated
+}
+
+
+/// A reader which notes the largest read asked of it.  The decoder reads
+/// into buffers sized for what it expects, so this bounds how much it
+/// allocates at once.
+pub struct MeasuredReader<'a> {
+    pub inner: BufReader<'a>,
+    pub largest_read: uint,
+}
+
+
+impl<'a> Reader for MeasuredReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if self.largest_read < buf.len() {
+            self.largest_read = buf.len();
+        }
+        self.inner.read(buf)
+    }
+}
+
+
+/// Decodes `stream` strictly and leniently and checks that neither pass
+/// allocates without bound, and that a stream is only accepted if it
+/// carries exactly `payload`.  Panics if it doesn't hold.
+pub fn check_decode(stream: &[u8], payload: &[u8]) {
+    for &strict in [true, false].iter() {
+        let mut input = MeasuredReader { inner: BufReader::new(stream), largest_read: 0 };
+        let mut output = Vec::new();
+        let result = match DecapOptions::new().strict(strict).decap(&mut input) {
+            Ok(mut decap) => decap.copy_to(&mut output),
+            Err(err) => Err(err)
+        };
+        // Pieces are read a chunk at a time, so the largest read is a
+        // whole record.
+        assert!(input.largest_read <= MAX_RECORD_SIZE,
+                "read of {} bytes asked for", input.largest_read);
+        match result {
+            Ok(digest) => {
+                assert!(output.as_slice() == payload, "wrong payload accepted");
+                let expected = sha256_of(&mut BufReader::new(payload)).unwrap();
+                assert!(digest.as_slice() == expected.as_slice(), "wrong digest returned");
+            },
+            Err(_) => ()
+        }
+    }
+    // The convenience wrapper must agree with the strict decoder.
+    let mut output = Vec::new();
+    match copy_out(&mut BufReader::new(stream), &mut output) {
+        Ok(_) => assert!(output.as_slice() == payload, "wrong payload accepted"),
+        Err(_) => ()
+    }
+}
+
+
+/// Mutates a stream from the corpus, with a donor from it, and checks the
+/// decoder on the result, every decision taken from `bytes`.  This is the
+/// `mutate` fuzz target, and replays what it found.
+pub fn check_steered(bytes: &[u8]) {
+    let corpus = corpus();
+    let mut choices = ByteChoices { bytes: bytes };
+    let (ref payload, ref stream) = corpus[choices.below(corpus.len())];
+    let (_, ref donor) = corpus[choices.below(corpus.len())];
+    let mutated = mutate(stream.as_slice(), donor.as_slice(), &mut choices);
+    check_decode(mutated.as_slice(), payload.as_slice());
+}
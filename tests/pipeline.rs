@@ -0,0 +1,96 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The pipelined decoder must accept and reject exactly what the plain
+//! one does.
+
+extern crate reliable_rw;
+
+use std::io::{MemReader, IoResult, IoError, OtherIoError};
+use reliable_rw::{DecapOptions, ReliableWriteError, PIECE_SIZE};
+use reliable_rw::pipeline::{copy_out, ReadAhead, WriteBehind};
+
+
+static MULTI_PIECE: &'static [u8] = include_bytes!("golden/multi-piece.bin");
+
+
+fn payload(length: uint) -> Vec<u8> {
+    range(0, length).map(|i| (i % 251) as u8).collect()
+}
+
+
+/// Takes `room` bytes, then fails.
+struct FullWriter {
+    room: uint,
+}
+
+
+impl Writer for FullWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        if self.room < buf.len() {
+            return Err(IoError { kind: OtherIoError, desc: "disk full", detail: None });
+        }
+        self.room -= buf.len();
+        Ok(())
+    }
+}
+
+
+#[test]
+fn pipelined_decode_matches_golden() {
+    let input = MemReader::new(MULTI_PIECE.to_vec());
+    match copy_out(&DecapOptions::new(), input, Vec::new()) {
+        Ok((_, output)) => assert!(output == payload(2 * PIECE_SIZE + 100)),
+        Err(err) => panic!("golden stream rejected: {}", err)
+    }
+}
+
+
+#[test]
+fn pipelined_decode_rejects_damage() {
+    let mut stream = MULTI_PIECE.to_vec();
+    stream[18 + PIECE_SIZE] ^= 0x01;
+    match copy_out(&DecapOptions::new(), MemReader::new(stream), Vec::new()) {
+        Err(ReliableWriteError::IntegrityError) => (),
+        Ok(_) => panic!("damaged stream accepted"),
+        Err(err) => panic!("wrong error: {}", err)
+    }
+    let stream = MULTI_PIECE.slice_to(PIECE_SIZE + 18).to_vec();
+    match copy_out(&DecapOptions::new(), MemReader::new(stream), Vec::new()) {
+        Err(ReliableWriteError::TruncatedError) => (),
+        Ok(_) => panic!("truncated stream accepted"),
+        Err(err) => panic!("wrong error: {}", err)
+    }
+}
+
+
+#[test]
+fn write_errors_are_reported() {
+    let input = MemReader::new(MULTI_PIECE.to_vec());
+    match copy_out(&DecapOptions::new(), input, FullWriter { room: 1000 }) {
+        Err(ReliableWriteError::WriteError(_)) => (),
+        Ok(_) => panic!("write error lost"),
+        Err(err) => panic!("wrong error: {}", err)
+    }
+}
+
+
+#[test]
+fn stages_pass_data_through_unchanged() {
+    let data = payload(300 * 1024 + 7);
+    let mut input = ReadAhead::new(MemReader::new(data.clone()), 2);
+    let mut output = WriteBehind::new(Vec::new(), 2);
+    let mut buf = [0u8; 1000];
+    loop {
+        match input.read(buf.as_mut_slice()) {
+            Ok(n) => output.write(buf.slice_to(n)).unwrap(),
+            Err(_) => break
+        }
+    }
+    assert!(output.finish().unwrap() == data);
+}
@@ -0,0 +1,269 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The `reliable-encap` built alongside, run against real commands.
+
+extern crate reliable_rw;
+
+use std::os;
+use std::io::{BufReader, Command, File, FilePermission, SeekSet, TempDir};
+use std::io::fs::{chmod, change_file_times, stat, unlink, PathExtensions};
+use std::io::process::{ExitStatus, ProcessOutput};
+use reliable_rw::{copy_out, exit_code, sha256_of, ReliableDecap, ReliableWriteError};
+use reliable_rw::sha256::to_hex;
+
+
+fn program(name: &str) -> Path {
+    os::self_exe_path().unwrap().join(name)
+}
+
+
+/// Runs `reliable-write` with `args` on `stream`.
+fn receive(args: &[&str], stream: &[u8]) -> ProcessOutput {
+    let mut process = Command::new(program("reliable-write")).args(args).spawn().unwrap();
+    // It may have given up before reading everything.
+    let _ = process.stdin.take().unwrap().write(stream);
+    process.wait_with_output().unwrap()
+}
+
+
+#[test]
+fn write_failure_kills_the_producer() {
+    let mut process = Command::new(program("reliable-encap"))
+        .args(&["--timeout", "60", "--", "sh", "-c", "head -c 1000000 /dev/zero; exec sleep 100"])
+        .spawn().unwrap();
+    // Nobody will read the stream.
+    drop(process.stdout.take());
+    process.set_timeout(Some(20000));
+    match process.wait() {
+        Ok(status) => assert!(status == ExitStatus(exit_code::WRITE_IO), "{}", status),
+        Err(err) => {
+            let _ = process.signal_kill();
+            panic!("still waiting for the producer: {}", err);
+        }
+    }
+}
+
+
+#[test]
+fn heartbeat_must_fit() {
+    // Zero would be all heartbeats, and the interval is sent as a u32 of
+    // milliseconds.
+    for secs in ["0", "4294968", "18446744073709552"].iter() {
+        let output = Command::new(program("reliable-encap"))
+            .args(&["--heartbeat", *secs, "--", "true"]).output().unwrap();
+        assert!(output.status == ExitStatus(exit_code::USAGE), "{}: {}", secs, output.status);
+    }
+    let output = Command::new(program("reliable-encap"))
+        .args(&["--timeout", "18446744073709552", "--", "true"]).output().unwrap();
+    assert!(output.status == ExitStatus(exit_code::USAGE), "{}", output.status);
+}
+
+
+#[test]
+fn heartbeats_while_producer_is_quiet() {
+    let output = Command::new(program("reliable-encap"))
+        .args(&["--heartbeat", "1", "--", "sh", "-c", "sleep 2; printf slow"]).output().unwrap();
+    assert!(output.status == ExitStatus(0), "{}", output.status);
+
+    let mut input = BufReader::new(output.output.as_slice());
+    let mut decap = ReliableDecap::new(&mut input).unwrap();
+    let mut payload = Vec::new();
+    decap.copy_to(&mut payload).unwrap();
+    assert!(payload.as_slice() == b"slow");
+    assert_eq!(decap.heartbeat_interval(), Some(1000));
+}
+
+
+#[test]
+fn heartbeats_keep_idle_receiver_waiting() {
+    // The producer is quiet for longer than the receiver's idle timeout,
+    // but not for longer than the heartbeat interval.
+    let dir = TempDir::new("reliable-encap").unwrap();
+    let target = dir.path().join("dest");
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg("\"$0\" --heartbeat 1 -- sh -c 'sleep 3; printf slow' | \"$1\" --idle-timeout 2 \"$2\"")
+        .arg(program("reliable-encap"))
+        .arg(program("reliable-write"))
+        .arg(&target)
+        .output().unwrap();
+    assert!(output.status == ExitStatus(0), "{}: {}",
+            output.status, String::from_utf8_lossy(output.error.as_slice()));
+    assert!(File::open(&target).read_to_end().unwrap().as_slice() == b"slow");
+}
+
+
+#[test]
+fn failed_producer_reported_despite_short_size() {
+    let dir = TempDir::new("reliable-encap").unwrap();
+    let target = dir.path().join("dest");
+    for &send_status in [true, false].iter() {
+        let mut args = vec!["--declared-size", "100"];
+        if send_status {
+            args.push("--send-status");
+        }
+        args.push_all(&["--", "sh", "-c", "printf abc; exit 3"]);
+        let sent = Command::new(program("reliable-encap")).args(args.as_slice()).output().unwrap();
+        assert!(sent.status == ExitStatus(exit_code::PRODUCER_FAILED), "{}", sent.status);
+
+        // Not a length mismatch: the producer's failure is what went wrong.
+        let received = receive(&[target.as_str().unwrap()], sent.output.as_slice());
+        let stderr = String::from_utf8_lossy(received.error.as_slice()).into_owned();
+        assert!(received.status == ExitStatus(exit_code::PRODUCER_FAILED), "{}: {}", received.status, stderr);
+        if send_status {
+            assert!(stderr.contains("exited with status 3"), "{}", stderr);
+        }
+        assert!(!target.exists());
+    }
+}
+
+
+#[test]
+fn file_past_declared_size_not_sent() {
+    let dir = TempDir::new("reliable-encap").unwrap();
+    let source = dir.path().join("source");
+    File::create(&source).write(b"grew past what was declared").unwrap();
+    let sent = Command::new(program("reliable-encap"))
+        .args(&["--declared-size", "4", "--file"]).arg(&source).output().unwrap();
+    assert!(sent.status == ExitStatus(exit_code::UNEXPECTED_CONTENT), "{}", sent.status);
+
+    // Nothing past the declared size was sent, and the stream ends as a
+    // failed producer's.
+    let mut payload = Vec::new();
+    match copy_out(&mut BufReader::new(sent.output.as_slice()), &mut payload) {
+        Err(ReliableWriteError::ProducerError(None)) => (),
+        other => panic!("wrong outcome: {:?}", other)
+    }
+    assert!(payload.len() <= 4);
+}
+
+
+#[test]
+fn file_sent_with_mode_and_mtime() {
+    let dir = TempDir::new("reliable-encap").unwrap();
+    let source = dir.path().join("source");
+    let target = dir.path().join("dest");
+    // A hole, then some data
+    {
+        let mut file = File::create(&source).unwrap();
+        file.seek(1 << 20, SeekSet).unwrap();
+        file.write(b"end").unwrap();
+    }
+    chmod(&source, FilePermission::from_bits_truncate(0o640)).unwrap();
+    change_file_times(&source, 1000000000000, 1000000000000).unwrap();
+
+    let sent = Command::new(program("reliable-encap")).arg("--file").arg(&source).output().unwrap();
+    assert!(sent.status == ExitStatus(0), "{}", sent.status);
+    let received = receive(&["--preserve", target.as_str().unwrap()], sent.output.as_slice());
+    assert!(received.status == ExitStatus(0), "{}: {}",
+            received.status, String::from_utf8_lossy(received.error.as_slice()));
+
+    assert!(File::open(&target).read_to_end().unwrap() == File::open(&source).read_to_end().unwrap());
+    let stat = stat(&target).unwrap();
+    assert_eq!(stat.perm.bits() & 0o777, 0o640);
+    assert_eq!(stat.modified, 1000000000000);
+}
+
+
+#[test]
+fn producer_failure_logged_with_stderr_tail() {
+    let dir = TempDir::new("reliable-encap").unwrap();
+    let target = dir.path().join("dest");
+    let log = dir.path().join("log");
+
+    let sent = Command::new(program("reliable-encap"))
+        .args(&["--stderr-tail", "1024", "--", "sh", "-c", "echo config is broken >&2; exit 3"])
+        .output().unwrap();
+    assert!(sent.status == ExitStatus(exit_code::PRODUCER_FAILED), "{}", sent.status);
+    let received = receive(&["--log", log.as_str().unwrap(), target.as_str().unwrap()], sent.output.as_slice());
+    assert!(received.status == ExitStatus(exit_code::PRODUCER_FAILED), "{}", received.status);
+    let stderr = String::from_utf8_lossy(received.error.as_slice()).into_owned();
+    assert!(stderr.contains("reliable-write: producer stderr: config is broken"), "{}", stderr);
+
+    let sent = Command::new(program("reliable-encap")).args(&["--", "printf", "payload"]).output().unwrap();
+    let received = receive(&["--log", log.as_str().unwrap(), target.as_str().unwrap()], sent.output.as_slice());
+    assert!(received.status == ExitStatus(0), "{}", received.status);
+
+    // One line per transfer, saying what became of it
+    let log = String::from_utf8(File::open(&log).read_to_end().unwrap()).unwrap();
+    let lines: Vec<&str> = log.as_slice().lines().collect();
+    assert_eq!(lines.len(), 2);
+    let failed = format!("{}: failed with status {}: producer failed: exited with status 3",
+                         target.display(), exit_code::PRODUCER_FAILED);
+    assert!(lines[0].contains(failed.as_slice()), "{}", lines[0]);
+    assert!(lines[0].ends_with(" stderr=\"config is broken\\n\""), "{}", lines[0]);
+    let committed = format!("{}: committed sha256={} size=7", target.display(),
+                            to_hex(sha256_of(&mut BufReader::new(b"payload")).unwrap().as_slice()));
+    assert!(lines[1].ends_with(committed.as_slice()), "{}", lines[1]);
+}
+
+
+#[test]
+fn piece_size_agreed_in_header() {
+    let dir = TempDir::new("reliable-encap").unwrap();
+    let target = dir.path().join("dest");
+    let producer = ["--", "head", "-c", "3000", "/dev/zero"];
+
+    let mut args = vec!["--piece-size", "1000"];
+    args.push_all(&producer);
+    let small = Command::new(program("reliable-encap")).args(args.as_slice()).output().unwrap();
+    assert!(small.status == ExitStatus(0), "{}", small.status);
+    {
+        let mut input = BufReader::new(small.output.as_slice());
+        let decap = ReliableDecap::new(&mut input).unwrap();
+        assert_eq!(decap.header().piece_size, Some(1000));
+    }
+    let default = Command::new(program("reliable-encap")).args(&producer).output().unwrap();
+    assert!(default.status == ExitStatus(0), "{}", default.status);
+
+    // The header's piece size is checked before any payload, and pieces
+    // are checked against the limit even without one.
+    for &(max, stream, expected) in [("1000", &small, exit_code::SUCCESS),
+                                     ("999", &small, exit_code::PROTOCOL),
+                                     ("1000", &default, exit_code::PROTOCOL)].iter() {
+        let received = receive(&["--max-piece-size", max, target.as_str().unwrap()], stream.output.as_slice());
+        assert!(received.status == ExitStatus(expected), "{}: {}", max, received.status);
+        assert_eq!(target.exists(), expected == exit_code::SUCCESS);
+        let _ = unlink(&target);
+    }
+}
+
+
+#[test]
+fn receipt_key_checked_through_the_transport() {
+    let dir = TempDir::new("reliable-encap").unwrap();
+    let target = dir.path().join("dest");
+    let source = dir.path().join("source");
+    let key = dir.path().join("key");
+    let other_key = dir.path().join("other-key");
+    File::create(&source).write(b"payload").unwrap();
+    File::create(&key).write(b"shared between the two ends").unwrap();
+    File::create(&other_key).write(b"known only to a forger").unwrap();
+
+    // Only a receipt signed with the sender's key is accepted; unsigned
+    // ones and ones signed with another key say the payload was committed,
+    // but are refused.
+    for &(receiver_key, expected) in [(Some(&key), exit_code::SUCCESS),
+                                      (None, exit_code::NO_RECEIPT),
+                                      (Some(&other_key), exit_code::NO_RECEIPT)].iter() {
+        let signing = match receiver_key {
+            Some(path) => format!("--receipt-key '{}'", path.display()),
+            None => String::new()
+        };
+        let transport = format!("'{}' --receipt {} '{}'", program("reliable-write").display(),
+                                signing, target.display());
+        let sent = Command::new(program("reliable-encap"))
+            .args(&["--receipt-key", key.as_str().unwrap(), "--transport", transport.as_slice(),
+                    "--file", source.as_str().unwrap()])
+            .output().unwrap();
+        assert!(sent.status == ExitStatus(expected), "{}: {}", transport, sent.status);
+        assert!(File::open(&target).read_to_end().unwrap().as_slice() == b"payload");
+        let _ = unlink(&target);
+    }
+}
@@ -0,0 +1,489 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The `reliable-write` built alongside, run on real streams: what it
+//! commits, how it exits, and what it leaves behind.
+
+extern crate reliable_rw;
+
+use std::os;
+use std::default::Default;
+use std::io::{BufReader, Command, File, FilePermission, IoResult, TempDir, USER_RWX};
+use std::io::fs::{readdir, readlink, mkdir, mkdir_recursive, symlink, stat, chmod, PathExtensions};
+use std::io::process::{ProcessExit, ExitStatus};
+use reliable_rw::{sha256_of, CommitReceipt, ReliableEncap, StreamHeader};
+use reliable_rw::sha256::to_hex;
+
+
+fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut stream = Vec::new();
+    {
+        let mut encapper = ReliableEncap::new(&mut stream).unwrap();
+        encapper.update(&payload.to_vec()).unwrap();
+        encapper.finish_write().unwrap();
+        encapper.finalize().unwrap();
+    }
+    stream
+}
+
+
+/// Encodes `payload` with its size declared in the header.
+fn encode_declared(payload: &[u8]) -> Vec<u8> {
+    let mut stream = Vec::new();
+    {
+        let mut header: StreamHeader = Default::default();
+        header.declared_size = Some(payload.len() as u64);
+        let mut encapper = ReliableEncap::with_header(&mut stream, &header).unwrap();
+        encapper.update(&payload.to_vec()).unwrap();
+        encapper.finish_write().unwrap();
+        encapper.finalize().unwrap();
+    }
+    stream
+}
+
+
+/// Runs `reliable-write` with `args`, calling `meddle` with its pid
+/// before sending it `stream`.  Returns how it exited and its stderr.
+fn run_meddled<F: FnOnce(String)>(args: &[&str], stream: &[u8], meddle: F) -> (ProcessExit, String) {
+    let program = os::self_exe_path().unwrap().join("reliable-write");
+    let mut process = Command::new(program).args(args).spawn().unwrap();
+    meddle(process.id().to_string());
+    // It may have given up before reading everything.
+    let _ = process.stdin.take().unwrap().write(stream);
+    let output = process.wait_with_output().unwrap();
+    (output.status, String::from_utf8_lossy(output.error.as_slice()).into_owned())
+}
+
+
+/// Runs `reliable-write` on `target`, calling `plant` with the name its
+/// temp file will be given before sending it `stream`.  It may create the
+/// file before `plant` gets there, so it is run again until `plant`
+/// succeeds.  Returns how that run exited and its stderr.
+fn run_planted<F: Fn(&Path) -> IoResult<()>>(target: &Path, stream: &[u8], plant: F) -> (ProcessExit, String) {
+    for _ in range(0u, 100) {
+        let mut planted = false;
+        let result = run_meddled(&[target.as_str().unwrap()], stream, |pid| {
+            let name = format!("{}.reliable-tmp.{}", target.filename_str().unwrap(), pid);
+            planted = plant(&target.with_filename(name)).is_ok();
+        });
+        if planted {
+            return result;
+        }
+    }
+    panic!("reliable-write created its temp file first every time");
+}
+
+
+fn run(args: &[&str], stream: &[u8]) -> (ProcessExit, String) {
+    run_meddled(args, stream, |_| ())
+}
+
+
+/// A tar archive of `files`, given as (name, content) pairs.
+fn tar_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let dir = TempDir::new("reliable-write-src").unwrap();
+    let mut cmd = Command::new("tar");
+    cmd.arg("-c").arg("-f").arg("-").arg("-C").arg(dir.path());
+    for &(name, content) in files.iter() {
+        File::create(&dir.path().join(name)).write(content).unwrap();
+        cmd.arg(name);
+    }
+    let output = cmd.output().unwrap();
+    assert!(output.status.success());
+    output.output
+}
+
+
+/// The names in `dir`, other than `keep`.
+fn leftovers(dir: &Path, keep: &[&str]) -> Vec<String> {
+    readdir(dir).unwrap().iter()
+        .map(|path| String::from_utf8_lossy(path.filename().unwrap()).into_owned())
+        .filter(|name| !keep.contains(&name.as_slice()))
+        .collect()
+}
+
+
+fn read(path: &Path) -> Vec<u8> {
+    File::open(path).read_to_end().unwrap()
+}
+
+
+fn sha256_hex(payload: &[u8]) -> String {
+    to_hex(sha256_of(&mut BufReader::new(payload)).unwrap().as_slice())
+}
+
+
+#[test]
+fn planted_symlink_not_followed() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+    let victim = dir.path().join("victim");
+    File::create(&victim).write(b"precious").unwrap();
+
+    let (status, stderr) = run_planted(&target, encode(b"payload").as_slice(), |temp| symlink(&victim, temp));
+    assert!(status == ExitStatus(0), "{}: {}", status, stderr);
+    assert!(read(&target).as_slice() == b"payload");
+    assert!(read(&victim).as_slice() == b"precious");
+}
+
+
+#[test]
+fn planted_lock_symlink_refused() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let victim = dir.path().join("victim");
+    File::create(&victim).write(b"precious").unwrap();
+    symlink(&victim, &dir.path().join("dest.lock")).unwrap();
+    // Were it followed, opening the lock would create this.
+    let missing = dir.path().join("missing");
+    symlink(&missing, &dir.path().join("other.lock")).unwrap();
+
+    for name in ["dest", "other"].iter() {
+        let target = dir.path().join(*name);
+        let (status, _) = run(&["--lock", target.as_str().unwrap()], encode(b"payload").as_slice());
+        assert!(status == ExitStatus(7), "{}", status);
+        assert!(!target.exists());
+    }
+    assert!(read(&victim).as_slice() == b"precious");
+    assert!(!missing.exists());
+    assert_eq!(leftovers(dir.path(), &["dest.lock", "other.lock", "victim"]), Vec::<String>::new());
+}
+
+
+#[test]
+fn stale_temp_of_the_other_kind_replaced() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+
+    // A staging directory left by a killed `--tree` run with our pid
+    let (status, stderr) = run_planted(&target, encode(b"payload").as_slice(), |temp| {
+        try!(mkdir(temp, USER_RWX));
+        File::create(&temp.join("partial")).write(b"stale")
+    });
+    assert!(status == ExitStatus(0), "{}: {}", status, stderr);
+    assert!(read(&target).as_slice() == b"payload");
+    assert_eq!(leftovers(dir.path(), &["dest"]), Vec::<String>::new());
+}
+
+
+#[test]
+fn closed_stdout_is_not_fatal() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+    let program = os::self_exe_path().unwrap().join("reliable-write");
+    let mut process = Command::new(program).arg("--receipt").arg(&target).spawn().unwrap();
+    // Nobody will read the receipt.
+    drop(process.stdout.take());
+    process.stdin.take().unwrap().write(encode(b"payload").as_slice()).unwrap();
+    let output = process.wait_with_output().unwrap();
+    assert!(output.status == ExitStatus(0), "{}", output.status);
+    assert!(read(&target).as_slice() == b"payload");
+}
+
+
+#[test]
+fn stalled_stream_times_out() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+    let program = os::self_exe_path().unwrap().join("reliable-write");
+    let mut process = Command::new(program).args(&["--idle-timeout", "1"]).arg(&target).spawn().unwrap();
+    // The stream starts, then stalls without being closed.
+    let mut input = process.stdin.take().unwrap();
+    input.write(encode(b"payload").slice_to(20)).unwrap();
+    process.set_timeout(Some(20000));
+    let status = process.wait();
+    drop(input);
+    match status {
+        Ok(status) => assert!(status == ExitStatus(15), "{}", status),
+        Err(err) => {
+            let _ = process.signal_kill();
+            panic!("still waiting for the stream: {}", err);
+        }
+    }
+    assert_eq!(leftovers(dir.path(), &[]), Vec::<String>::new());
+}
+
+
+#[test]
+fn tree_keeps_old_tree_after_swap() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("tree");
+    let old = dir.path().join("tree.old");
+    mkdir_recursive(&target, USER_RWX).unwrap();
+    File::create(&target.join("a")).write(b"current").unwrap();
+    mkdir_recursive(&old, USER_RWX).unwrap();
+    File::create(&old.join("a")).write(b"previous").unwrap();
+
+    let stream = encode(tar_of(&[("a", b"next")]).as_slice());
+    let (status, stderr) = run(&["--tree", "--keep-old", target.as_str().unwrap()], stream.as_slice());
+    assert!(status == ExitStatus(0), "{}: {}", status, stderr);
+    assert!(read(&target.join("a")).as_slice() == b"next");
+    assert!(read(&old.join("a")).as_slice() == b"current");
+    assert_eq!(leftovers(dir.path(), &["tree", "tree.old"]), Vec::<String>::new());
+}
+
+
+#[test]
+fn tree_root_keeps_old_mode() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("tree");
+    mkdir_recursive(&target, USER_RWX).unwrap();
+    chmod(&target, FilePermission::from_bits_truncate(0o750)).unwrap();
+
+    // Named files only, so the archive has no `./` entry to set the mode.
+    let stream = encode(tar_of(&[("a", b"next")]).as_slice());
+    let (status, stderr) = run(&["--tree", target.as_str().unwrap()], stream.as_slice());
+    assert!(status == ExitStatus(0), "{}: {}", status, stderr);
+    assert!(read(&target.join("a")).as_slice() == b"next");
+    assert_eq!(stat(&target).unwrap().perm.bits() & 0o777, 0o750);
+}
+
+
+#[test]
+fn tree_refuses_preserve() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("tree");
+    let stream = encode(tar_of(&[("a", b"next")]).as_slice());
+    let (status, _) = run(&["--tree", "--preserve", target.as_str().unwrap()], stream.as_slice());
+    assert!(status == ExitStatus(2), "{}", status);
+    assert!(!target.exists());
+}
+
+
+#[test]
+fn tree_refuses_linked_old() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("tree");
+    let victim = dir.path().join("victim");
+    mkdir_recursive(&target, USER_RWX).unwrap();
+    File::create(&target.join("a")).write(b"current").unwrap();
+    mkdir_recursive(&victim, USER_RWX).unwrap();
+    File::create(&victim.join("keep")).write(b"precious").unwrap();
+    symlink(&victim, &dir.path().join("tree.old")).unwrap();
+
+    let stream = encode(tar_of(&[("a", b"next")]).as_slice());
+    let (status, _) = run(&["--tree", "--keep-old", target.as_str().unwrap()], stream.as_slice());
+    assert!(status == ExitStatus(8), "{}", status);
+    assert!(read(&target.join("a")).as_slice() == b"current");
+    assert!(read(&victim.join("keep")).as_slice() == b"precious");
+    assert_eq!(leftovers(dir.path(), &["tree", "tree.old", "victim"]), Vec::<String>::new());
+}
+
+
+#[test]
+fn tree_refuses_target_which_is_not_a_directory() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let victim = dir.path().join("victim");
+    mkdir_recursive(&victim, USER_RWX).unwrap();
+    File::create(&victim.join("keep")).write(b"precious").unwrap();
+    let linked = dir.path().join("linked");
+    symlink(&victim, &linked).unwrap();
+    let file = dir.path().join("file");
+    File::create(&file).write(b"current").unwrap();
+
+    let stream = encode(tar_of(&[("a", b"next")]).as_slice());
+    for target in [&linked, &file].iter() {
+        let (status, _) = run(&["--tree", target.as_str().unwrap()], stream.as_slice());
+        assert!(status == ExitStatus(8), "{}: {}", target.display(), status);
+    }
+    assert!(readlink(&linked).unwrap() == victim);
+    assert!(read(&victim.join("keep")).as_slice() == b"precious");
+    assert!(read(&file).as_slice() == b"current");
+    assert_eq!(leftovers(dir.path(), &["file", "linked", "victim"]), Vec::<String>::new());
+}
+
+
+#[test]
+fn unexpected_payload_refused() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+    let expected = sha256_hex(b"payload");
+
+    // The stream verifies, but isn't what was meant to be deployed.
+    for args in [["--expect-sha256", expected.as_slice()], ["--expect-size", "7"]].iter() {
+        let (status, _) = run(&[args[0], args[1], target.as_str().unwrap()], encode(b"tampered").as_slice());
+        assert!(status == ExitStatus(9), "{}: {}", args[0], status);
+        assert_eq!(leftovers(dir.path(), &[]), Vec::<String>::new());
+    }
+
+    let (status, stderr) = run(&["--expect-sha256", expected.as_slice(), "--expect-size", "7",
+                                 target.as_str().unwrap()], encode(b"payload").as_slice());
+    assert!(status == ExitStatus(0), "{}: {}", status, stderr);
+    assert!(read(&target).as_slice() == b"payload");
+}
+
+
+#[test]
+fn hooks_see_the_temp_file_only_while_it_exists() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let seen = TempDir::new("reliable-write-hooks").unwrap();
+    let target = dir.path().join("dest");
+    let pre = format!("cat \"$RELIABLE_WRITE_TEMP\" > '{0}/temp'; \
+                       printf '%s %s %s' \"$RELIABLE_WRITE_TARGET\" \"$RELIABLE_WRITE_SHA256\" \
+                       \"$RELIABLE_WRITE_SIZE\" > '{0}/pre'", seen.path().display());
+    let post = format!("printf '%s %s' \"${{RELIABLE_WRITE_TEMP-unset}}\" \"$RELIABLE_WRITE_SHA256\" > '{}/post'",
+                       seen.path().display());
+    let (status, stderr) = run(&["--pre-commit", pre.as_slice(), "--post-commit", post.as_slice(),
+                                 target.as_str().unwrap()], encode(b"payload").as_slice());
+    assert!(status == ExitStatus(0), "{}: {}", status, stderr);
+    assert!(read(&seen.path().join("temp")).as_slice() == b"payload");
+    assert_eq!(String::from_utf8(read(&seen.path().join("pre"))).unwrap(),
+               format!("{} {} 7", target.display(), sha256_hex(b"payload")));
+    assert_eq!(String::from_utf8(read(&seen.path().join("post"))).unwrap(),
+               format!("unset {}", sha256_hex(b"payload")));
+
+    // A veto leaves the target alone, and the abort hook runs after the
+    // temp file is gone.
+    let abort = format!("printf '%s %s' \"${{RELIABLE_WRITE_TEMP-unset}}\" \"$RELIABLE_WRITE_STATUS\" > '{}/abort'",
+                        seen.path().display());
+    let (status, _) = run(&["--pre-commit", "exit 1", "--on-abort", abort.as_slice(),
+                            target.as_str().unwrap()], encode(b"vetoed").as_slice());
+    assert!(status == ExitStatus(10), "{}", status);
+    assert!(read(&target).as_slice() == b"payload");
+    assert_eq!(leftovers(dir.path(), &["dest"]), Vec::<String>::new());
+    assert_eq!(String::from_utf8(read(&seen.path().join("abort"))).unwrap(), "unset 10".to_string());
+}
+
+
+#[test]
+fn compare_and_swap_refuses_a_changed_target() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+    let based_on = sha256_hex(b"original");
+
+    // Missing, then changed since the sender looked
+    for current in [None, Some(b"edited on the host")].iter() {
+        match *current {
+            Some(content) => File::create(&target).write(content).unwrap(),
+            None => ()
+        }
+        let (status, _) = run(&["--if-match", based_on.as_slice(), target.as_str().unwrap()],
+                              encode(b"payload").as_slice());
+        assert!(status == ExitStatus(13), "{}", status);
+        assert_eq!(target.exists(), current.is_some());
+        assert_eq!(leftovers(dir.path(), &["dest", "dest.lock"]), Vec::<String>::new());
+    }
+    let (status, _) = run(&["--if-absent", target.as_str().unwrap()], encode(b"payload").as_slice());
+    assert!(status == ExitStatus(13), "{}", status);
+    assert!(read(&target).as_slice() == b"edited on the host");
+
+    File::create(&target).write(b"original").unwrap();
+    let (status, stderr) = run(&["--if-match", based_on.as_slice(), target.as_str().unwrap()],
+                               encode(b"payload").as_slice());
+    assert!(status == ExitStatus(0), "{}: {}", status, stderr);
+    assert!(read(&target).as_slice() == b"payload");
+}
+
+
+#[test]
+fn declared_size_checked_against_free_space() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+    let stream = encode_declared(b"payload");
+
+    // No filesystem has this much to spare, and adding the size to it
+    // mustn't wrap around.
+    for reserve in ["4611686018427387904", "18446744073709551615"].iter() {
+        let (status, _) = run(&["--reserve", *reserve, target.as_str().unwrap()], stream.as_slice());
+        assert!(status == ExitStatus(14), "{}: {}", reserve, status);
+        assert_eq!(leftovers(dir.path(), &[]), Vec::<String>::new());
+    }
+    // Without a declared size, only the headroom can be checked.
+    let (status, _) = run(&["--reserve", "4611686018427387904", target.as_str().unwrap()],
+                          encode(b"payload").as_slice());
+    assert!(status == ExitStatus(14), "{}", status);
+    assert_eq!(leftovers(dir.path(), &[]), Vec::<String>::new());
+
+    let (status, stderr) = run(&["--reserve", "0", target.as_str().unwrap()], stream.as_slice());
+    assert!(status == ExitStatus(0), "{}: {}", status, stderr);
+    assert!(read(&target).as_slice() == b"payload");
+}
+
+
+#[test]
+fn size_limits_enforced() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+    File::create(&dir.path().join("other")).write([0u8; 100].as_slice()).unwrap();
+    File::create(&target).write([0u8; 1000].as_slice()).unwrap();
+
+    // Refused from the header when the size is declared, and as soon as it
+    // goes over when not.  The target being replaced doesn't count
+    // towards the quota.
+    for stream in [encode_declared(b"payload"), encode(b"payload")].iter() {
+        for limit in [["--max-size", "6"], ["--quota", "106"]].iter() {
+            let (status, _) = run(&[limit[0], limit[1], target.as_str().unwrap()], stream.as_slice());
+            assert!(status == ExitStatus(16), "{}: {}", limit[0], status);
+            assert_eq!(read(&target).len(), 1000);
+            assert_eq!(leftovers(dir.path(), &["dest", "other"]), Vec::<String>::new());
+        }
+    }
+    let (status, stderr) = run(&["--max-size", "7", "--quota", "107", target.as_str().unwrap()],
+                               encode(b"payload").as_slice());
+    assert!(status == ExitStatus(0), "{}: {}", status, stderr);
+    assert!(read(&target).as_slice() == b"payload");
+}
+
+
+#[test]
+fn receipt_reports_the_outcome() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+    let program = os::self_exe_path().unwrap().join("reliable-write");
+    let mut damaged = encode(b"payload");
+    damaged[20] ^= 0x01;
+
+    for &(ref stream, status) in [(encode(b"payload"), 0), (damaged, 3)].iter() {
+        let mut process = Command::new(&program).arg("--receipt").arg(&target).spawn().unwrap();
+        let _ = process.stdin.take().unwrap().write(stream.as_slice());
+        let output = process.wait_with_output().unwrap();
+        assert!(output.status == ExitStatus(status), "{}", output.status);
+
+        // A receipt is all there is on stdout, for failures too.
+        let receipt = CommitReceipt::read_from(&mut BufReader::new(output.output.as_slice())).unwrap();
+        assert_eq!(receipt.status, status as u32);
+        assert!(receipt.path == target.as_vec().to_vec());
+        if status == 0 {
+            assert_eq!(receipt.size, 7);
+            assert_eq!(to_hex(receipt.sha256.as_slice()), sha256_hex(b"payload"));
+        } else {
+            assert_eq!(receipt.size, 0);
+            assert!(receipt.sha256.as_slice() == [0u8; 32].as_slice());
+        }
+    }
+}
+
+
+#[test]
+fn lock_wait_must_fit() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+    // Its milliseconds would wrap around to a wait of 384 ms.
+    let (status, _) = run(&["--lock-wait", "18446744073709552", target.as_str().unwrap()],
+                          encode(b"payload").as_slice());
+    assert!(status == ExitStatus(2), "{}", status);
+    assert!(!target.exists());
+}
+
+
+#[test]
+fn pre_commit_hook_cannot_change_the_payload() {
+    let dir = TempDir::new("reliable-write").unwrap();
+    let target = dir.path().join("dest");
+    File::create(&target).write(b"original").unwrap();
+    let expected = sha256_hex(b"payload");
+
+    // Written to in place, and replaced by another file
+    for hook in ["printf tampered > \"$RELIABLE_WRITE_TEMP\"",
+                 "printf payload > \"$RELIABLE_WRITE_TEMP.new\" && mv \"$RELIABLE_WRITE_TEMP.new\" \"$RELIABLE_WRITE_TEMP\""].iter() {
+        let (status, _) = run(&["--expect-sha256", expected.as_slice(), "--pre-commit", *hook,
+                                target.as_str().unwrap()], encode(b"payload").as_slice());
+        assert!(status == ExitStatus(3), "{}: {}", hook, status);
+        assert!(read(&target).as_slice() == b"original");
+        assert_eq!(leftovers(dir.path(), &["dest"]), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2014 Stacey Ell <stacey.ell@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Quoting and destination parsing for `reliable-send`, and a stream
+//! carried through `sh -c` to the `reliable-write` built alongside.
+
+extern crate reliable_rw;
+
+use std::os;
+use std::default::Default;
+use std::io::{Command, File, IoResult, TempDir};
+use reliable_rw::{EncapOptions, ReceiptKey, StreamHeader};
+use reliable_rw::transport::{shell_quote, split_dest, Transport, Delivery};
+
+
+#[test]
+fn shell_quote_survives_the_shell() {
+    let args: [&[u8]; 6] = [b"", b"plain", b"two words", b"it's", b"'''", b"$HOME `id` \\ \"x\" *"];
+    for &arg in args.iter() {
+        let mut cmd = b"printf %s ".to_vec();
+        cmd.push_all(shell_quote(arg).as_slice());
+        let output = Command::new("sh").arg("-c").arg(cmd.as_slice()).output().unwrap();
+        assert!(output.output.as_slice() == arg, "{} came back as {}",
+                String::from_utf8_lossy(arg), String::from_utf8_lossy(output.output.as_slice()));
+    }
+}
+
+
+#[test]
+fn split_dest_follows_scp() {
+    let split = |&: host: Option<&str>, path: &str| Some((host.map(|h| h.to_string()), path.to_string()));
+    assert_eq!(split_dest("host:dest"), split(Some("host"), "dest"));
+    assert_eq!(split_dest("user@host:/srv/dest"), split(Some("user@host"), "/srv/dest"));
+    assert_eq!(split_dest("host:a:b"), split(Some("host"), "a:b"));
+    assert_eq!(split_dest("dest"), split(None, "dest"));
+    assert_eq!(split_dest("./a:b"), split(None, "./a:b"));
+    assert_eq!(split_dest("dir/a:b"), split(None, "dir/a:b"));
+    assert_eq!(split_dest(":dest"), split(None, ":dest"));
+}
+
+
+#[test]
+fn split_dest_rejects_options() {
+    // ssh would take the host for an option, and reliable-write the path.
+    assert_eq!(split_dest("-oProxyCommand=touch /tmp/x:dest"), None);
+    assert_eq!(split_dest("-v:dest"), None);
+    assert_eq!(split_dest("host:-x"), None);
+    assert_eq!(split_dest("-x"), None);
+    assert_eq!(split_dest("host:"), None);
+}
+
+
+fn encode(output: &mut Writer, header: StreamHeader, payload: &[u8]) -> IoResult<[u8; 32]> {
+    let mut encapper = try!(EncapOptions::new().header(header).encap(output));
+    try!(encapper.update(&payload.to_vec()));
+    try!(encapper.finish_write());
+    try!(encapper.finalize());
+    Ok(encapper.sha256())
+}
+
+
+/// Starts `reliable-write --receipt`, with `options` as well, on `target`
+/// through `sh -c`.
+fn receiver(options: &[u8], target: &Path) -> Transport {
+    let receiver = os::self_exe_path().unwrap().join("reliable-write");
+    let mut cmd = shell_quote(receiver.as_vec());
+    cmd.push_all(b" --receipt ");
+    cmd.push_all(options);
+    cmd.push(b' ');
+    cmd.push_all(shell_quote(target.as_vec()).as_slice());
+    Transport::shell(cmd.as_slice()).unwrap()
+}
+
+
+/// Sends `payload` to `reliable-write --receipt` on `target`, claiming to
+/// have sent `claimed` bytes.
+fn deliver(target: &Path, payload: &[u8], claimed: u64) -> Delivery {
+    let mut transport = receiver(b"", target);
+    // A receiver which gives up may stop reading before we are done.
+    let sent = encode(&mut transport.input(), Default::default(), payload).ok();
+    transport.finish(sent.map(|digest| (digest, claimed)))
+}
+
+
+/// Sends `payload` to `reliable-write --receipt` on `target`, run with
+/// `options`, with `nonce` in the header.  Only a receipt signed with the
+/// key in `key` for `expected` is accepted.
+fn deliver_signed(target: &Path, options: &[u8], key: &Path, nonce: [u8; 16], expected: [u8; 16]) -> Delivery {
+    let mut transport = receiver(options, target);
+    transport.require_signed(ReceiptKey::read_from(key).unwrap(), expected);
+    let header = StreamHeader { receipt_nonce: Some(nonce), ..Default::default() };
+    let sent = encode(&mut transport.input(), header, b"payload").unwrap();
+    transport.finish(Some((sent, 7)))
+}
+
+
+#[test]
+fn delivered_through_the_shell() {
+    let dir = TempDir::new("reliable-send").unwrap();
+    let target = dir.path().join("dest");
+    match deliver(&target, b"hello, world\n", 13) {
+        Delivery::Committed(receipt) => assert_eq!(receipt.size, 13),
+        other => panic!("not delivered: {}", other)
+    }
+    assert!(File::open(&target).read_to_end().unwrap().as_slice() == b"hello, world\n");
+}
+
+
+#[test]
+fn mismatch_reported() {
+    let dir = TempDir::new("reliable-send").unwrap();
+    let target = dir.path().join("dest");
+    match deliver(&target, b"hello, world\n", 12) {
+        Delivery::Mismatch(receipt) => assert_eq!(receipt.size, 13),
+        other => panic!("mismatch not noticed: {}", other)
+    }
+}
+
+
+#[test]
+fn rejection_reported() {
+    // The receiver can't create its temp file in a directory which
+    // doesn't exist.
+    let dir = TempDir::new("reliable-send").unwrap();
+    let target = dir.path().join("missing").join("dest");
+    match deliver(&target, b"hello, world\n", 13) {
+        Delivery::Rejected(receipt) => assert!(receipt.status != 0),
+        other => panic!("rejection not noticed: {}", other)
+    }
+}
+
+
+#[test]
+fn signed_receipt_checked() {
+    let dir = TempDir::new("reliable-send").unwrap();
+    let target = dir.path().join("dest");
+    let key = dir.path().join("key");
+    let other_key = dir.path().join("other-key");
+    File::create(&key).write(b"shared between the two ends").unwrap();
+    File::create(&other_key).write(b"known only to a forger").unwrap();
+    let mut key_option = b"--receipt-key ".to_vec();
+    key_option.push_all(shell_quote(key.as_vec()).as_slice());
+    let mut other_key_option = b"--receipt-key ".to_vec();
+    other_key_option.push_all(shell_quote(other_key.as_vec()).as_slice());
+    let nonce = ReceiptKey::new_nonce().unwrap();
+    let other_nonce = ReceiptKey::new_nonce().unwrap();
+
+    match deliver_signed(&target, key_option.as_slice(), &key, nonce, nonce) {
+        Delivery::Committed(receipt) => assert!(receipt.mac.is_some()),
+        other => panic!("signed receipt refused: {}", other)
+    }
+    // An unsigned receipt, one signed with another key and one signed for
+    // another stream are all refused, though each says the payload was
+    // committed.
+    let refused: [(&[u8], [u8; 16]); 3] = [
+        (b"", nonce),
+        (other_key_option.as_slice(), nonce),
+        (key_option.as_slice(), other_nonce),
+    ];
+    for &(options, expected) in refused.iter() {
+        match deliver_signed(&target, options, &key, nonce, expected) {
+            Delivery::Unverified(receipt) => assert_eq!(receipt.status, 0),
+            other => panic!("forged receipt accepted: {}", other)
+        }
+    }
+}